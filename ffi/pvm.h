@@ -0,0 +1,80 @@
+/*
+ * C ABI for PVMs loaded by pvm-test-harness as `[[pvm]] kind = "ffi"`.
+ *
+ * The shared library must export all of the functions below. They mirror the
+ * `PvmApi` trait: setters configure the initial state, `pvm_run`/`pvm_step`
+ * execute and getters query the resulting state.
+ *
+ * All functions receive the opaque handle returned by `pvm_create`. The
+ * harness never calls into the same handle from multiple threads.
+ *
+ * There is no way to select the instruction set yet, only 64-bit programs are
+ * run through this interface.
+ */
+#ifndef PVM_TEST_HARNESS_PVM_H
+#define PVM_TEST_HARNESS_PVM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define PVM_NUMBER_OF_REGISTERS 13
+
+/* Execution status, returned by `pvm_run` and `pvm_step`. Negative values indicate an error. */
+#define PVM_STATUS_OK 255
+#define PVM_STATUS_HALT 0
+#define PVM_STATUS_PANIC 1
+#define PVM_STATUS_FAULT 2
+#define PVM_STATUS_HOST 3
+#define PVM_STATUS_OUT_OF_GAS 4
+
+/* Program container passed to `pvm_set_program`. */
+#define PVM_CONTAINER_GENERIC 0
+#define PVM_CONTAINER_SPI 1
+#define PVM_CONTAINER_POLKAVM 2
+
+/* Result codes of `pvm_set_program`. */
+#define PVM_PROGRAM_OK 0
+#define PVM_PROGRAM_INVALID 1
+#define PVM_PROGRAM_UNSUPPORTED_CONTAINER 2
+
+/* Page access passed to `pvm_set_page`. */
+#define PVM_ACCESS_READABLE 0
+#define PVM_ACCESS_WRITEABLE 1
+
+typedef void pvm_t;
+
+/* Create a new PVM instance. Returns NULL on failure. */
+pvm_t *pvm_create(void);
+/* Release all resources of the instance. */
+void pvm_destroy(pvm_t *pvm);
+
+/* Run until the program stops (halt, panic, fault, host call or out of gas). */
+int32_t pvm_run(pvm_t *pvm);
+/* Execute a single instruction. Returns `PVM_STATUS_OK` if the execution can continue. */
+int32_t pvm_step(pvm_t *pvm);
+
+/* Page-aligned address of the last page fault (valid after `PVM_STATUS_FAULT` was returned). */
+uint32_t pvm_get_fault_address(const pvm_t *pvm);
+
+int64_t pvm_get_gas(const pvm_t *pvm);
+void pvm_set_gas(pvm_t *pvm, int64_t gas);
+
+/* `registers` points to `PVM_NUMBER_OF_REGISTERS` values. */
+void pvm_get_registers(const pvm_t *pvm, uint64_t *registers);
+void pvm_set_registers(pvm_t *pvm, const uint64_t *registers);
+
+/* Writes the current program counter to `pc` and returns 1, or returns 0 if it's unknown. */
+int32_t pvm_get_pc(const pvm_t *pvm, uint32_t *pc);
+void pvm_set_next_pc(pvm_t *pvm, uint32_t pc);
+
+/* Returns one of `PVM_PROGRAM_*` codes. Also resets the memory (pages and contents). */
+int32_t pvm_set_program(pvm_t *pvm, const uint8_t *code, size_t length, uint32_t container);
+
+/* Make the page accessible and zeroed. `page` is the page index, i.e. `address / 4096`. */
+void pvm_set_page(pvm_t *pvm, uint32_t page, uint32_t access);
+
+/* Both return 0 on success and non-zero if the memory is not accessible. */
+int32_t pvm_read_memory(const pvm_t *pvm, uint32_t address, uint8_t *out, size_t length);
+int32_t pvm_write_memory(pvm_t *pvm, uint32_t address, const uint8_t *data, size_t length);
+
+#endif /* PVM_TEST_HARNESS_PVM_H */
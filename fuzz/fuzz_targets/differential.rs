@@ -0,0 +1,189 @@
+//! Runs structured programs decoded from the fuzzer input on all in-process PVMs and panics
+//! if they disagree on the outcome.
+//!
+//! Compares the polkavm interpreter with the recompiler (if supported on this platform).
+//! Additional PVMs can be given with `PVM_FUZZ_FFI=<library>` and `PVM_FUZZ_WASM=<module>`.
+//! PVMs which don't support 32-bit programs (e.g. shared libraries and WebAssembly modules, see
+//! `ffi/pvm.h`) are left out of the comparison for 32-bit inputs.
+
+#![no_main]
+
+use std::cell::RefCell;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use pvm_test_harness::{
+    api::{ffi::FfiPvm, polkavm::PolkaVm, wasm::WasmPvm, Isa, PvmApi, PAGE_SIZE},
+    bridge,
+    json::{MemoryChunk, Page, TestcaseJson},
+    program::GenericProgram,
+};
+
+const MAX_INSTRUCTIONS: usize = 256;
+const MAX_ARGS: usize = 16;
+/// Address of the first page which can be mapped.
+const MEMORY_START: u32 = 0x10000;
+
+#[derive(Arbitrary, Debug)]
+struct Instruction {
+    opcode: u8,
+    args: Vec<u8>,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    instructions: Vec<Instruction>,
+    /// Indices of instructions to put into the jump table.
+    jump_table: Vec<u8>,
+    registers: [u64; 13],
+    gas: u16,
+    /// Pages (above `MEMORY_START`) to map and whether they are writable.
+    pages: Vec<(u8, bool)>,
+    /// Initial memory chunks, placed in the mapped pages (in order) at given offset.
+    memory: Vec<(u16, Vec<u8>)>,
+    is_32_bit: bool,
+}
+
+/// Outcome of the execution: status, fault address, registers, pc, gas and memory.
+type Outcome = (String, Option<u32>, Vec<u64>, u32, i64, Vec<MemoryChunk>);
+
+fn program(input: &Input) -> Vec<u8> {
+    let mut program = GenericProgram::default();
+    let mut starts = vec![];
+    for instruction in input.instructions.iter().take(MAX_INSTRUCTIONS) {
+        starts.push(program.code.len() as u32);
+        let args = &instruction.args[..instruction.args.len().min(MAX_ARGS)];
+        program.code.push(instruction.opcode);
+        program.code.extend_from_slice(args);
+        program.bitmask.push(true);
+        program.bitmask.resize(program.bitmask.len() + args.len(), false);
+    }
+    // make sure the program doesn't end in the middle of an instruction.
+    program.code.push(0);
+    program.bitmask.push(true);
+    program.jump_table = input
+        .jump_table
+        .iter()
+        .filter_map(|index| starts.get(*index as usize).copied())
+        .collect();
+    program.encode()
+}
+
+fn testcase(input: &Input) -> TestcaseJson {
+    let mut pages = input.pages.clone();
+    pages.sort();
+    pages.dedup_by_key(|(index, _)| *index);
+    let page_address = |index: u8| MEMORY_START + u32::from(index) * PAGE_SIZE;
+
+    let initial_memory = if pages.is_empty() {
+        vec![]
+    } else {
+        input
+            .memory
+            .iter()
+            .zip(pages.iter().cycle())
+            .map(|((offset, contents), (index, _))| {
+                let offset = u32::from(*offset) % PAGE_SIZE;
+                let len = contents.len().min((PAGE_SIZE - offset) as usize);
+                MemoryChunk {
+                    address: page_address(*index) + offset,
+                    contents: contents[..len].to_vec(),
+                }
+            })
+            .collect()
+    };
+
+    TestcaseJson {
+        name: "fuzz".into(),
+        isa: if input.is_32_bit { Isa::Bits32 } else { Isa::Bits64 },
+        initial_regs: input.registers,
+        initial_page_map: pages
+            .iter()
+            .map(|(index, writable)| Page {
+                address: page_address(*index),
+                length: PAGE_SIZE,
+                is_writable: *writable,
+            })
+            .collect(),
+        initial_memory,
+        initial_gas: i64::from(input.gas),
+        program: program(input),
+        ..Default::default()
+    }
+}
+
+fn outcome(json: TestcaseJson) -> Outcome {
+    (
+        json.expected_status,
+        json.expected_page_fault_address,
+        json.expected_regs,
+        json.expected_pc,
+        json.expected_gas,
+        json.expected_memory,
+    )
+}
+
+/// A PVM along with its name and whether it can run 32-bit programs.
+type Target = (String, Box<dyn PvmApi>, bool);
+
+fn pvms() -> Vec<Target> {
+    let mut pvms: Vec<(String, Box<dyn PvmApi>)> = vec![("interpreter".into(), Box::new(PolkaVm::default()))];
+
+    // only use the recompiler if it can run a trivial program on this platform.
+    let mut compiler = PolkaVm::compiler();
+    let trap = TestcaseJson {
+        program: GenericProgram {
+            code: vec![0],
+            bitmask: vec![true],
+            ..Default::default()
+        }
+        .encode(),
+        ..Default::default()
+    };
+    match bridge::execute(&mut compiler, trap) {
+        Ok(_) => pvms.push(("compiler".into(), Box::new(compiler))),
+        Err(e) => eprintln!("polkavm recompiler is not available: {e}"),
+    }
+
+    if let Ok(path) = std::env::var("PVM_FUZZ_FFI") {
+        let pvm = FfiPvm::load(path.as_ref()).expect("Unable to load PVM_FUZZ_FFI");
+        pvms.push((path, Box::new(pvm)));
+    }
+    if let Ok(path) = std::env::var("PVM_FUZZ_WASM") {
+        let pvm = WasmPvm::load(path.as_ref()).expect("Unable to load PVM_FUZZ_WASM");
+        pvms.push((path, Box::new(pvm)));
+    }
+    pvms.into_iter()
+        .map(|(name, mut pvm)| {
+            // the ISA is set again for every test case.
+            let supports_32_bit = pvm.set_isa(Isa::Bits32).is_ok();
+            (name, pvm, supports_32_bit)
+        })
+        .collect()
+}
+
+thread_local! {
+    static PVMS: RefCell<Vec<Target>> = RefCell::new(pvms());
+}
+
+fuzz_target!(|input: Input| {
+    let json = testcase(&input);
+    PVMS.with_borrow_mut(|pvms| {
+        let is_32_bit = json.isa == Isa::Bits32;
+        let targets = pvms
+            .iter_mut()
+            .filter(|(_, _, supports_32_bit)| *supports_32_bit || !is_32_bit);
+        let mut outcomes = targets.map(|(name, pvm, _)| {
+            let result = bridge::execute(pvm.as_mut(), json.clone()).map(outcome);
+            (name.as_str(), result.map_err(|e| e.to_string()))
+        });
+        let (first_name, first) = outcomes.next().expect("there is always the interpreter; qed");
+        for (name, result) in outcomes {
+            // errors are only compared by their presence, the messages differ between PVMs.
+            match (&first, &result) {
+                (Err(_), Err(_)) => {}
+                _ => assert_eq!(first, result, "{first_name} and {name} diverge on {json:?}"),
+            }
+        }
+    });
+});
@@ -1,105 +1,528 @@
-use super::PvmApi;
+use std::{cell::RefCell, cmp::Reverse, fmt, str::FromStr};
+
+use super::{Isa, PvmApi, Snapshot};
+
+/// Which of the PVMs is trusted when their results differ.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Policy {
+    /// The first PVM in the list.
+    #[default]
+    First,
+    /// The PVM with given name.
+    Oracle(String),
+    /// The PVM whose state (status, pc, gas and registers) after the execution is shared by most
+    /// PVMs (the earliest one on a tie), the others are reported as wrong.
+    Majority,
+    /// None, the PVMs are only checked against the expected values of the test case.
+    ///
+    /// There is nothing to compare in a collection, so it can't be used with `PvmApiCollection`.
+    ExpectedOnly,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Policy::First),
+            "majority" => Ok(Policy::Majority),
+            "expected-only" => Ok(Policy::ExpectedOnly),
+            _ => match s.strip_prefix("oracle=") {
+                Some(name) if !name.is_empty() => Ok(Policy::Oracle(name.to_string())),
+                _ => Err(format!(
+                    "Invalid policy: {s:?}, expected 'first', 'oracle=<name>', 'majority' or 'expected-only'"
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Policy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::First => write!(f, "first"),
+            Policy::Oracle(name) => write!(f, "oracle={name}"),
+            Policy::Majority => write!(f, "majority"),
+            Policy::ExpectedOnly => write!(f, "expected-only"),
+        }
+    }
+}
+
+/// Values returned by the PVMs which didn't agree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// What the PVMs disagree on: `status`, `gas`, `pc`, `r<N>`, `memory` or the `PvmApi` method.
+    pub field: String,
+    /// Name of each PVM along with the `Debug` representation of the value it returned.
+    pub values: Vec<(String, String)>,
+    /// Names of the PVMs which returned a different value than the trusted one or an error.
+    pub wrong: Vec<String>,
+}
 
 pub struct PvmApiCollection {
+    names: Vec<String>,
     collection: Vec<Box<dyn PvmApi>>,
+    policy: Policy,
+    /// The PVM elected by the `Majority` policy after the last execution.
+    elected: Option<usize>,
+    mismatches: RefCell<Vec<Mismatch>>,
+    /// PVMs which failed during the current test and are ignored until the next one.
+    quarantined: RefCell<Vec<bool>>,
 }
 
 impl PvmApiCollection {
-    pub fn new(collection: Vec<Box<dyn PvmApi>>) -> Self {
-        assert!(!collection.is_empty());
+    pub fn new(pvms: Vec<(String, Box<dyn PvmApi>)>) -> Self {
+        assert!(!pvms.is_empty());
+
+        let quarantined = RefCell::new(vec![false; pvms.len()]);
+        let (names, collection) = pvms.into_iter().unzip();
+        Self {
+            names,
+            collection,
+            policy: Policy::default(),
+            elected: None,
+            mismatches: Default::default(),
+            quarantined,
+        }
+    }
+
+    /// Use given policy to decide which PVM is trusted.
+    ///
+    /// Fails if the oracle is not in the collection or for `Policy::ExpectedOnly`.
+    pub fn with_policy(mut self, policy: Policy) -> super::Result<Self> {
+        match &policy {
+            Policy::Oracle(name) if !self.names.contains(name) => {
+                return Err(super::Error::Other(format!("Unknown oracle PVM: {name:?}")));
+            }
+            Policy::ExpectedOnly => {
+                return Err(super::Error::Other(
+                    "The 'expected-only' policy doesn't compare PVMs, so it can't be used with a collection.".into(),
+                ));
+            }
+            _ => {}
+        }
+        self.policy = policy;
+        Ok(self)
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
 
-        Self { collection }
+    /// Names of the PVMs in the collection.
+    pub fn names(&self) -> &[String] {
+        &self.names
     }
 
-    fn for_all_mut<F, G, R>(&mut self, mut run: F, merge: G) -> R
+    /// Run on all PVMs which are not quarantined, returning their indices along with the results.
+    fn for_all_mut<F, R>(&mut self, mut run: F) -> Vec<(usize, R)>
     where
         F: FnMut(&mut dyn PvmApi) -> R,
-        G: Fn(R, R) -> R,
     {
-        let mut result = run(self.collection[0].as_mut());
-        for pvm in &mut self.collection[1..] {
-            result = merge(result, run(pvm.as_mut()));
-        }
-        result
+        let quarantined = self.quarantined.get_mut();
+        self.collection
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| !quarantined[*i])
+            .map(|(i, pvm)| (i, run(pvm.as_mut())))
+            .collect()
     }
 
-    fn for_all<F, G, R>(&self, run: F, merge: G) -> R
+    fn for_all<F, R>(&self, run: F) -> Vec<(usize, R)>
     where
         F: Fn(&dyn PvmApi) -> R,
-        G: Fn(R, R) -> R,
     {
-        let mut result = run(self.collection[0].as_ref());
-        for pvm in &self.collection[1..] {
-            result = merge(result, run(pvm.as_ref()));
+        let quarantined = self.quarantined.borrow();
+        self.collection
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !quarantined[*i])
+            .map(|(i, pvm)| (i, run(pvm.as_ref())))
+            .collect()
+    }
+
+    /// Elect the PVM to trust with the `Majority` policy after an execution, by comparing the
+    /// whole state of the PVMs which didn't fail.
+    fn elect(&mut self, results: &[(usize, super::Result<super::Status>)]) {
+        if self.policy != Policy::Majority {
+            return;
         }
-        result
+        let states: Vec<_> = results
+            .iter()
+            .filter_map(|(i, status)| {
+                let pvm = &self.collection[*i];
+                let status = status.as_ref().ok()?;
+                Some((*i, (*status, pvm.program_counter(), pvm.gas(), pvm.registers())))
+            })
+            .collect();
+        self.elected = (0..states.len())
+            .max_by_key(|k| (states.iter().filter(|(_, s)| *s == states[*k].1).count(), Reverse(*k)))
+            .map(|k| states[k].0);
     }
-}
 
-fn propagate<R: core::fmt::Debug + Eq>(a: R, b: R, ctx: &str) -> R {
-    if a != b {
-        log::error!("[{ctx}] PVM status mismatch: {a:?} vs {b:?}");
+    /// Position of the result to trust according to the policy.
+    ///
+    /// With `Majority`, values are taken from the elected PVM. Before anything was executed (or if
+    /// it's quarantined), the most common value is trusted.
+    fn trusted<R: Eq>(&self, results: &[(usize, R)]) -> usize {
+        match &self.policy {
+            Policy::First | Policy::ExpectedOnly => 0,
+            Policy::Oracle(name) => results
+                .iter()
+                .position(|(i, _)| self.names[*i] == *name)
+                .expect("the oracle is never quarantined; qed"),
+            Policy::Majority => match self.elected.and_then(|e| results.iter().position(|(i, _)| *i == e)) {
+                Some(elected) => elected,
+                None => (0..results.len())
+                    .max_by_key(|k| (results.iter().filter(|(_, r)| *r == results[*k].1).count(), Reverse(*k)))
+                    .expect("at least one PVM is never quarantined; qed"),
+            },
+        }
     }
-    a
-}
-fn propagate_res<R: core::fmt::Debug + Eq>(a: super::Result<R>, b: super::Result<R>, ctx: &str) -> super::Result<R> {
-    match (a, b) {
-        (Err(e), o) | (o, Err(e)) => {
-            log::error!("[{ctx}] Ignoring result due to error in one of the PVMs: {o:?}");
-            Err(e)
+
+    /// `Debug` representation of each result along with the name of the PVM.
+    fn values<R: core::fmt::Debug>(&self, results: &[(usize, R)]) -> Vec<(String, String)> {
+        results
+            .iter()
+            .map(|(i, r)| (self.names[*i].clone(), format!("{r:?}")))
+            .collect()
+    }
+
+    /// Return the trusted result, recording a mismatch if any of the others differ.
+    fn propagate<R: core::fmt::Debug + Eq>(&self, results: Vec<(usize, R)>, ctx: &str) -> R {
+        let trusted = self.trusted(&results);
+        self.compare(&results, trusted, ctx);
+        results.into_iter().nth(trusted).expect("the index is in range; qed").1
+    }
+
+    /// Record a mismatch if the results differ.
+    fn compare<R: core::fmt::Debug + Eq>(&self, results: &[(usize, R)], trusted: usize, field: &str) {
+        let expected = &results[trusted].1;
+        if results.iter().all(|(_, r)| r == expected) {
+            return;
         }
-        (Ok(a), Ok(b)) => Ok(propagate(a, b, ctx)),
+        let wrong: Vec<_> = results
+            .iter()
+            .filter(|(_, r)| r != expected)
+            .map(|(i, _)| self.names[*i].clone())
+            .collect();
+        let values = self.values(results);
+        log::error!("[{field}] PVM status mismatch ({} wrong): {values:?}", wrong.join(", "));
+        self.mismatches.borrow_mut().push(Mismatch {
+            field: field.to_string(),
+            values,
+            wrong,
+        });
+    }
+
+    /// Quarantine the PVMs which failed until the next test and propagate the results of the others.
+    ///
+    /// The failures are recorded as a mismatch. The (first) error is returned if all PVMs fail, or
+    /// if the oracle does, since there is nothing to compare the others with.
+    fn propagate_res<R: core::fmt::Debug + Eq>(
+        &self,
+        results: Vec<(usize, super::Result<R>)>,
+        ctx: &str,
+    ) -> super::Result<R> {
+        if results.iter().all(|(_, r)| r.is_err()) {
+            log::error!("[{ctx}] All PVMs failed.");
+            return results
+                .into_iter()
+                .next()
+                .expect("at least one PVM is never quarantined; qed")
+                .1;
+        }
+        if let Policy::Oracle(name) = &self.policy {
+            let oracle = results.iter().find(|(i, _)| self.names[*i] == *name);
+            if let Some((_, Err(e))) = oracle {
+                log::error!("[{ctx}] The oracle {name} failed: {e}");
+                return Err(super::Error::Other(format!("The oracle {name} failed: {e}")));
+            }
+        }
+        if results.iter().any(|(_, r)| r.is_err()) {
+            let mut quarantined = self.quarantined.borrow_mut();
+            let mut wrong = vec![];
+            for (i, result) in &results {
+                if let Err(e) = result {
+                    log::error!(
+                        "[{ctx}] {} failed, ignoring it until the next test: {e}",
+                        self.names[*i]
+                    );
+                    quarantined[*i] = true;
+                    wrong.push(self.names[*i].clone());
+                }
+            }
+            self.mismatches.borrow_mut().push(Mismatch {
+                field: ctx.to_string(),
+                values: self.values(&results),
+                wrong,
+            });
+        }
+        let results = results
+            .into_iter()
+            .filter_map(|(i, r)| r.ok().map(|r| (i, r)))
+            .collect();
+        Ok(self.propagate(results, ctx))
     }
 }
 
 impl PvmApi for PvmApiCollection {
     fn run(&mut self) -> super::Result<super::Status> {
-        self.for_all_mut(|p| p.run(), |a, b| propagate_res(a, b, "run"))
+        let results = self.for_all_mut(|p| p.run());
+        self.elect(&results);
+        self.propagate_res(results, "status")
+    }
+
+    fn step(&mut self) -> super::Result<super::Status> {
+        let results = self.for_all_mut(|p| p.step());
+        self.elect(&results);
+        self.propagate_res(results, "status")
     }
 
     fn gas(&self) -> i64 {
-        self.for_all(|p| p.gas(), |a, b| propagate(a, b, "gas"))
+        self.propagate(self.for_all(|p| p.gas()), "gas")
     }
 
     fn set_gas(&mut self, gas: i64) {
-        self.for_all_mut(|p| p.set_gas(gas), |a, b| propagate(a, b, "set_gas"))
+        self.for_all_mut(|p| p.set_gas(gas));
     }
 
     fn registers(&self) -> [u64; super::NUMBER_OF_REGISTERS] {
-        self.for_all(|p| p.registers(), |a, b| propagate(a, b, "registers"))
+        let results = self.for_all(|p| p.registers());
+        let trusted = self.trusted(&results);
+        for index in 0..super::NUMBER_OF_REGISTERS {
+            let values: Vec<_> = results.iter().map(|(i, r)| (*i, r[index])).collect();
+            self.compare(&values, trusted, &format!("r{index}"));
+        }
+        results[trusted].1
     }
 
     fn set_registers(&mut self, registers: &[u64; super::NUMBER_OF_REGISTERS]) {
-        self.for_all_mut(|p| p.set_registers(registers), |a, b| propagate(a, b, "set_registers"))
+        self.for_all_mut(|p| p.set_registers(registers));
     }
 
     fn program_counter(&self) -> Option<u32> {
-        self.for_all(|p| p.program_counter(), |a, b| propagate(a, b, "PC"))
+        self.propagate(self.for_all(|p| p.program_counter()), "pc")
     }
 
     fn set_next_program_counter(&mut self, pc: u32) {
-        self.for_all_mut(|p| p.set_next_program_counter(pc), |a, b| propagate(a, b, "set_PC"))
+        self.for_all_mut(|p| p.set_next_program_counter(pc));
+    }
+
+    /// Lift the quarantine of the PVMs which failed during the previous test.
+    fn begin_testcase(&mut self) {
+        self.quarantined.get_mut().fill(false);
+        self.elected = None;
+        self.for_all_mut(|p| p.begin_testcase());
+    }
+
+    fn set_isa(&mut self, isa: Isa) -> super::Result<()> {
+        let results = self.for_all_mut(|p| p.set_isa(isa));
+        self.propagate_res(results, "set_isa")
     }
 
     fn set_program(&mut self, code: &[u8], container: super::ProgramContainer) -> super::Result<()> {
-        self.for_all_mut(
-            |p| p.set_program(code, container),
-            |a, b| propagate_res(a, b, "set_program"),
-        )
+        let results = self.for_all_mut(|p| p.set_program(code, container));
+        self.propagate_res(results, "set_program")
     }
 
     fn set_page(&mut self, page: u32, access: super::MemoryAccess) {
-        self.for_all_mut(|p| p.set_page(page, access), |a, b| propagate(a, b, "set_page"))
+        self.for_all_mut(|p| p.set_page(page, access));
     }
 
-    fn read_memory(&self, _address: u32, _out: &mut [u8]) -> super::Result<()> {
-        todo!()
+    fn read_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        let len = out.len();
+        let results = self.for_all(|p| {
+            let mut data = vec![0u8; len];
+            p.read_memory(address, &mut data).map(|_| data)
+        });
+        let data = self.propagate_res(results, "memory")?;
+        out.copy_from_slice(&data);
+        Ok(())
     }
 
     fn write_memory(&mut self, address: u32, data: &[u8]) -> super::Result<()> {
-        self.for_all_mut(
-            |p| p.write_memory(address, data),
-            |a, b| propagate_res(a, b, "write_memory"),
-        )
+        let results = self.for_all_mut(|p| p.write_memory(address, data));
+        self.propagate_res(results, "write_memory")
+    }
+
+    fn diagnostics(&self) -> Option<String> {
+        let all: Vec<_> = self.collection.iter().filter_map(|p| p.diagnostics()).collect();
+        (!all.is_empty()).then(|| all.join("\n"))
+    }
+
+    fn take_mismatches(&self) -> Vec<Mismatch> {
+        self.mismatches.take()
+    }
+
+    fn snapshot(&self) -> super::Result<Snapshot> {
+        self.propagate_res(self.for_all(|p| p.snapshot()), "snapshot")
+    }
+
+    fn restore(&mut self, snapshot: &Snapshot) -> super::Result<()> {
+        let results = self.for_all_mut(|p| p.restore(snapshot));
+        self.propagate_res(results, "restore")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{self, MemoryAccess, ProgramContainer, Status, NUMBER_OF_REGISTERS};
+
+    /// A PVM which finishes with given status, gas and `r0`, or fails to run.
+    struct Fixed(Option<(Status, i64, u64)>);
+
+    impl PvmApi for Fixed {
+        fn run(&mut self) -> api::Result<Status> {
+            self.0
+                .map(|(status, _, _)| status)
+                .ok_or_else(|| api::Error::Other("crashed".into()))
+        }
+        fn gas(&self) -> i64 {
+            self.0.map_or(0, |(_, gas, _)| gas)
+        }
+        fn set_gas(&mut self, _gas: i64) {}
+        fn registers(&self) -> [u64; NUMBER_OF_REGISTERS] {
+            let mut registers = [0; NUMBER_OF_REGISTERS];
+            registers[0] = self.0.map_or(0, |(_, _, r0)| r0);
+            registers
+        }
+        fn set_registers(&mut self, _registers: &[u64; NUMBER_OF_REGISTERS]) {}
+        fn program_counter(&self) -> Option<u32> {
+            Some(0)
+        }
+        fn set_next_program_counter(&mut self, _pc: u32) {}
+        fn set_program(&mut self, _code: &[u8], _container: ProgramContainer) -> api::Result<()> {
+            Ok(())
+        }
+        fn set_page(&mut self, _page: u32, _access: MemoryAccess) {}
+        fn read_memory(&self, _address: u32, _out: &mut [u8]) -> api::Result<()> {
+            Ok(())
+        }
+        fn write_memory(&mut self, _address: u32, _data: &[u8]) -> api::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn collection(pvms: Vec<Option<(Status, i64, u64)>>, policy: Policy) -> PvmApiCollection {
+        let pvms = pvms
+            .into_iter()
+            .enumerate()
+            .map(|(i, pvm)| (format!("pvm{i}"), Box::new(Fixed(pvm)) as Box<dyn PvmApi>))
+            .collect();
+        PvmApiCollection::new(pvms).with_policy(policy).unwrap()
+    }
+
+    fn wrong(pvms: &PvmApiCollection) -> Vec<(String, Vec<String>)> {
+        pvms.take_mismatches().into_iter().map(|m| (m.field, m.wrong)).collect()
+    }
+
+    #[test]
+    fn policies_parse() {
+        assert_eq!("first".parse(), Ok(Policy::First));
+        assert_eq!("oracle=ref".parse(), Ok(Policy::Oracle("ref".into())));
+        assert_eq!("majority".parse(), Ok(Policy::Majority));
+        assert_eq!("expected-only".parse(), Ok(Policy::ExpectedOnly));
+        assert!("oracle=".parse::<Policy>().is_err());
+        assert_eq!(Policy::Oracle("ref".into()).to_string(), "oracle=ref");
+    }
+
+    #[test]
+    fn expected_only_and_unknown_oracles_are_rejected() {
+        let pvms = || vec![("a".to_string(), Box::new(Fixed(None)) as Box<dyn PvmApi>)];
+        assert!(PvmApiCollection::new(pvms()).with_policy(Policy::ExpectedOnly).is_err());
+        assert!(PvmApiCollection::new(pvms())
+            .with_policy(Policy::Oracle("b".into()))
+            .is_err());
+    }
+
+    #[test]
+    fn majority_takes_all_values_from_one_pvm() {
+        // by field, gas would be taken from pvm1 and r0 from pvm0.
+        let mut pvms = collection(
+            vec![
+                Some((Status::Halt, 4, 1)),
+                Some((Status::Halt, 5, 2)),
+                Some((Status::Halt, 5, 3)),
+            ],
+            Policy::Majority,
+        );
+        assert_eq!(pvms.run().unwrap(), Status::Halt);
+        assert_eq!((pvms.gas(), pvms.registers()[0]), (4, 1));
+
+        let mut pvms = collection(
+            vec![
+                Some((Status::Halt, 4, 1)),
+                Some((Status::Halt, 5, 2)),
+                Some((Status::Halt, 5, 2)),
+            ],
+            Policy::Majority,
+        );
+        pvms.run().unwrap();
+        assert_eq!((pvms.gas(), pvms.registers()[0]), (5, 2));
+        assert_eq!(
+            wrong(&pvms),
+            [("gas".into(), vec!["pvm0".into()]), ("r0".into(), vec!["pvm0".into()])]
+        );
+    }
+
+    #[test]
+    fn oracle_is_trusted() {
+        let mut pvms = collection(
+            vec![Some((Status::Halt, 4, 1)), Some((Status::Trap, 5, 2))],
+            Policy::Oracle("pvm1".into()),
+        );
+        assert_eq!(pvms.run().unwrap(), Status::Trap);
+        assert_eq!(pvms.gas(), 5);
+        assert_eq!(
+            wrong(&pvms),
+            [
+                ("status".into(), vec!["pvm0".into()]),
+                ("gas".into(), vec!["pvm0".into()])
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_pvms_are_quarantined_until_the_next_test() {
+        let mut pvms = collection(
+            vec![None, Some((Status::Halt, 4, 1)), Some((Status::Halt, 5, 1))],
+            Policy::First,
+        );
+        assert_eq!(pvms.run().unwrap(), Status::Halt);
+        assert_eq!(pvms.gas(), 4);
+        assert_eq!(
+            wrong(&pvms),
+            [
+                ("status".into(), vec!["pvm0".into()]),
+                ("gas".into(), vec!["pvm2".into()])
+            ]
+        );
+
+        // still quarantined, so the error is not reported again.
+        pvms.run().unwrap();
+        assert_eq!(wrong(&pvms), []);
+        pvms.begin_testcase();
+        pvms.run().unwrap();
+        assert_eq!(wrong(&pvms)[0], ("status".into(), vec!["pvm0".into()]));
+    }
+
+    #[test]
+    fn failing_oracle_is_an_error() {
+        let mut pvms = collection(vec![Some((Status::Halt, 4, 1)), None], Policy::Oracle("pvm1".into()));
+        assert!(pvms.run().is_err());
+
+        let mut pvms = collection(vec![None, None], Policy::First);
+        assert!(pvms.run().is_err());
     }
 }
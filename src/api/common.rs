@@ -1,4 +1,4 @@
-use super::ProgramContainer;
+use super::{Error, Isa, MemoryAccess, ProgramContainer, Snapshot, Status, PAGE_SIZE};
 
 #[derive(Debug, Default)]
 pub(crate) struct InitialState {
@@ -7,6 +7,108 @@ pub(crate) struct InitialState {
     pub pc: u32,
     pub program: Vec<u8>,
     pub container: Option<ProgramContainer>,
+    pub isa: Isa,
+    /// Accessible pages (by index).
+    pub pages: Vec<(u32, MemoryAccess)>,
+    /// Memory writes applied on top of zeroed pages.
+    pub memory: Vec<(u32, Vec<u8>)>,
+}
+
+impl InitialState {
+    pub fn set_page(&mut self, page: u32, access: MemoryAccess) {
+        self.pages.retain(|(p, _)| *p != page);
+        self.pages.push((page, access));
+    }
+
+    /// Replace the initial state with the snapshot.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers = snapshot.registers;
+        self.gas = snapshot.gas;
+        self.pc = snapshot.pc;
+        self.pages = snapshot
+            .pages
+            .iter()
+            .map(|(page, access, _)| (*page, *access))
+            .collect();
+        self.memory = snapshot
+            .pages
+            .iter()
+            .map(|(page, _, data)| (page * PAGE_SIZE, data.clone()))
+            .collect();
+    }
+
+    /// Read the initial memory contents, failing if any byte is outside of accessible pages.
+    pub fn read_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        read_initial_memory(
+            |page| self.pages.iter().any(|(p, _)| *p == page),
+            self.memory.iter().map(|(a, d)| (*a, &d[..])),
+            address,
+            out,
+        )
+    }
+}
+
+/// Read memory consisting of zeroed accessible pages with `chunks` written on top.
+pub(crate) fn read_initial_memory<'a>(
+    is_accessible: impl Fn(u32) -> bool,
+    chunks: impl IntoIterator<Item = (u32, &'a [u8])>,
+    address: u32,
+    out: &mut [u8],
+) -> super::Result<()> {
+    let end = u64::from(address) + out.len() as u64;
+    let mut page = address / PAGE_SIZE;
+    while u64::from(page) * u64::from(PAGE_SIZE) < end {
+        if !is_accessible(page) {
+            return Err(Error::Other(format!("Page {page} is not accessible.")));
+        }
+        page += 1;
+    }
+
+    out.fill(0);
+    for (chunk_address, data) in chunks {
+        copy_overlap(address, out, chunk_address, data);
+    }
+    Ok(())
+}
+
+/// Read memory from a list of chunks, failing if any byte is not covered.
+pub(crate) fn read_chunks<'a>(
+    chunks: impl IntoIterator<Item = (u32, &'a [u8])>,
+    address: u32,
+    out: &mut [u8],
+) -> super::Result<()> {
+    let mut covered = vec![false; out.len()];
+    for (chunk_address, data) in chunks {
+        for i in copy_overlap(address, out, chunk_address, data) {
+            covered[i] = true;
+        }
+    }
+    match covered.iter().position(|c| !c) {
+        None => Ok(()),
+        Some(i) => Err(Error::Other(format!(
+            "Memory at {} is not available.",
+            u64::from(address) + i as u64
+        ))),
+    }
+}
+
+/// Copy the part of `data` (located at `data_address`) overlapping `out` (located at `address`).
+///
+/// Returns the range of `out` that was written.
+pub(crate) fn copy_overlap(address: u32, out: &mut [u8], data_address: u32, data: &[u8]) -> std::ops::Range<usize> {
+    let out_start = u64::from(address);
+    let out_end = out_start + out.len() as u64;
+    let data_start = u64::from(data_address);
+    let data_end = data_start + data.len() as u64;
+
+    let start = out_start.max(data_start);
+    let end = out_end.min(data_end);
+    if start >= end {
+        return 0..0;
+    }
+    let range = (start - out_start) as usize..(end - out_start) as usize;
+    out[range.clone()].copy_from_slice(&data[(start - data_start) as usize..(end - data_start) as usize]);
+    range
 }
 
 #[derive(Debug, Default)]
@@ -14,4 +116,51 @@ pub(crate) struct OutputState {
     pub registers: [u64; super::NUMBER_OF_REGISTERS],
     pub gas: i64,
     pub pc: Option<u32>,
+    /// Memory reported after execution, `None` if nothing was executed yet.
+    pub memory: Option<Vec<(u32, Vec<u8>)>>,
+}
+
+// Codes defined in `ffi/pvm.h`, shared by the shared library and WebAssembly PVMs.
+
+/// Status returned by `pvm_run` and `pvm_step`, negative values are errors.
+pub(crate) fn status_from_code(code: i32, fault_address: impl FnOnce() -> u32) -> super::Result<Status> {
+    u8::try_from(code)
+        .ok()
+        .and_then(|code| Status::from_code(code, fault_address))
+        .ok_or_else(|| Error::Other(format!("PVM returned an error: {code}")))
+}
+
+/// `PVM_CONTAINER_*` code passed to `pvm_set_program`.
+pub(crate) fn container_code(container: ProgramContainer) -> u32 {
+    match container {
+        ProgramContainer::Generic => 0,
+        ProgramContainer::Spi => 1,
+        ProgramContainer::PolkaVM => 2,
+    }
+}
+
+/// Result of `pvm_set_program`.
+pub(crate) fn program_result(code: i32) -> super::Result<()> {
+    match code {
+        0 => Ok(()),
+        1 => Err(Error::InvalidProgram),
+        2 => Err(Error::UnsupportedContainer),
+        code => Err(Error::Other(format!("set_program failed: {code}"))),
+    }
+}
+
+/// `PVM_ACCESS_*` code passed to `pvm_set_page`.
+pub(crate) fn access_code(access: MemoryAccess) -> u32 {
+    match access {
+        MemoryAccess::Readable => 0,
+        MemoryAccess::Writeable => 1,
+    }
+}
+
+/// Result of `pvm_read_memory` and `pvm_write_memory`.
+pub(crate) fn memory_result(code: i32, function: &str, address: u32) -> super::Result<()> {
+    match code {
+        0 => Ok(()),
+        code => Err(Error::Other(format!("{function} at {address} failed: {code}"))),
+    }
 }
@@ -0,0 +1,152 @@
+//! PVMs loaded from shared libraries exporting the C interface described in `ffi/pvm.h`.
+
+use std::{ffi::c_void, path::Path};
+
+use libloading::Library;
+
+use super::{common, Error, MemoryAccess, ProgramContainer, PvmApi, Status, NUMBER_OF_REGISTERS};
+
+type Handle = *mut c_void;
+
+struct Symbols {
+    destroy: unsafe extern "C" fn(Handle),
+    run: unsafe extern "C" fn(Handle) -> i32,
+    step: unsafe extern "C" fn(Handle) -> i32,
+    get_fault_address: unsafe extern "C" fn(Handle) -> u32,
+    get_gas: unsafe extern "C" fn(Handle) -> i64,
+    set_gas: unsafe extern "C" fn(Handle, i64),
+    get_registers: unsafe extern "C" fn(Handle, *mut u64),
+    set_registers: unsafe extern "C" fn(Handle, *const u64),
+    get_pc: unsafe extern "C" fn(Handle, *mut u32) -> i32,
+    set_next_pc: unsafe extern "C" fn(Handle, u32),
+    set_program: unsafe extern "C" fn(Handle, *const u8, usize, u32) -> i32,
+    set_page: unsafe extern "C" fn(Handle, u32, u32),
+    read_memory: unsafe extern "C" fn(Handle, u32, *mut u8, usize) -> i32,
+    write_memory: unsafe extern "C" fn(Handle, u32, *const u8, usize) -> i32,
+}
+
+pub struct FfiPvm {
+    handle: Handle,
+    symbols: Symbols,
+    // NOTE: must outlive `symbols`, so it's only dropped after `pvm_destroy` is called.
+    _library: Library,
+}
+
+impl FfiPvm {
+    /// Load the shared library and create a new PVM instance.
+    pub fn load(path: &Path) -> super::Result<Self> {
+        // SAFETY: loading a library runs its initialisers; we trust the user-provided PVM.
+        let library = unsafe { Library::new(path) }.map_err(Error::wrap)?;
+
+        let create: unsafe extern "C" fn() -> Handle = symbol(&library, "pvm_create")?;
+        let symbols = Symbols {
+            destroy: symbol(&library, "pvm_destroy")?,
+            run: symbol(&library, "pvm_run")?,
+            step: symbol(&library, "pvm_step")?,
+            get_fault_address: symbol(&library, "pvm_get_fault_address")?,
+            get_gas: symbol(&library, "pvm_get_gas")?,
+            set_gas: symbol(&library, "pvm_set_gas")?,
+            get_registers: symbol(&library, "pvm_get_registers")?,
+            set_registers: symbol(&library, "pvm_set_registers")?,
+            get_pc: symbol(&library, "pvm_get_pc")?,
+            set_next_pc: symbol(&library, "pvm_set_next_pc")?,
+            set_program: symbol(&library, "pvm_set_program")?,
+            set_page: symbol(&library, "pvm_set_page")?,
+            read_memory: symbol(&library, "pvm_read_memory")?,
+            write_memory: symbol(&library, "pvm_write_memory")?,
+        };
+
+        // SAFETY: `pvm_create` takes no arguments.
+        let handle = unsafe { create() };
+        if handle.is_null() {
+            return Err(Error::Other(format!("pvm_create returned NULL in {}", path.display())));
+        }
+
+        Ok(Self {
+            handle,
+            symbols,
+            _library: library,
+        })
+    }
+
+    fn status(&self, code: i32) -> super::Result<Status> {
+        // SAFETY: see below.
+        let fault_address = || unsafe { (self.symbols.get_fault_address)(self.handle) };
+        common::status_from_code(code, fault_address)
+    }
+}
+
+fn symbol<T: Copy>(library: &Library, name: &str) -> super::Result<T> {
+    // SAFETY: the signature is defined by `ffi/pvm.h`.
+    let symbol = unsafe { library.get::<T>(name.as_bytes()) }.map_err(Error::wrap)?;
+    Ok(*symbol)
+}
+
+impl Drop for FfiPvm {
+    fn drop(&mut self) {
+        // SAFETY: the handle was created by `pvm_create` and is not used afterwards.
+        unsafe { (self.symbols.destroy)(self.handle) }
+    }
+}
+
+// SAFETY (for all calls below): `handle` is a live instance created by `pvm_create` and all
+// pointers passed point to buffers of the length declared in `ffi/pvm.h`.
+impl PvmApi for FfiPvm {
+    fn run(&mut self) -> super::Result<Status> {
+        let code = unsafe { (self.symbols.run)(self.handle) };
+        self.status(code)
+    }
+
+    fn step(&mut self) -> super::Result<Status> {
+        let code = unsafe { (self.symbols.step)(self.handle) };
+        self.status(code)
+    }
+
+    fn gas(&self) -> i64 {
+        unsafe { (self.symbols.get_gas)(self.handle) }
+    }
+
+    fn set_gas(&mut self, gas: i64) {
+        unsafe { (self.symbols.set_gas)(self.handle, gas) }
+    }
+
+    fn registers(&self) -> [u64; NUMBER_OF_REGISTERS] {
+        let mut registers = [0u64; NUMBER_OF_REGISTERS];
+        unsafe { (self.symbols.get_registers)(self.handle, registers.as_mut_ptr()) };
+        registers
+    }
+
+    fn set_registers(&mut self, registers: &[u64; NUMBER_OF_REGISTERS]) {
+        unsafe { (self.symbols.set_registers)(self.handle, registers.as_ptr()) }
+    }
+
+    fn program_counter(&self) -> Option<u32> {
+        let mut pc = 0u32;
+        let known = unsafe { (self.symbols.get_pc)(self.handle, &mut pc) };
+        (known != 0).then_some(pc)
+    }
+
+    fn set_next_program_counter(&mut self, pc: u32) {
+        unsafe { (self.symbols.set_next_pc)(self.handle, pc) }
+    }
+
+    fn set_program(&mut self, code: &[u8], container: ProgramContainer) -> super::Result<()> {
+        let container = common::container_code(container);
+        let res = unsafe { (self.symbols.set_program)(self.handle, code.as_ptr(), code.len(), container) };
+        common::program_result(res)
+    }
+
+    fn set_page(&mut self, page: u32, access: MemoryAccess) {
+        unsafe { (self.symbols.set_page)(self.handle, page, common::access_code(access)) }
+    }
+
+    fn read_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        let res = unsafe { (self.symbols.read_memory)(self.handle, address, out.as_mut_ptr(), out.len()) };
+        common::memory_result(res, "read_memory", address)
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> super::Result<()> {
+        let res = unsafe { (self.symbols.write_memory)(self.handle, address, data.as_ptr(), data.len()) };
+        common::memory_result(res, "write_memory", address)
+    }
+}
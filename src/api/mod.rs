@@ -1,20 +1,50 @@
 pub mod collection;
 mod common;
+pub mod ffi;
 pub mod polkavm;
+pub mod reference;
 pub mod stdin;
+pub mod wasm;
 
 pub const NUMBER_OF_REGISTERS: usize = 13;
+pub const PAGE_SIZE: u32 = 4096;
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[repr(u8)]
 pub enum Status {
     Ok = 255,
     Halt = 0,
     Trap = 1,
-    Fault = 2,
+    /// Page fault at given (page-aligned) address.
+    Fault(u32) = 2,
     Host = 3,
     OutOfGas = 4,
 }
 
+impl Status {
+    /// Decode the numeric status code (see the enum discriminants).
+    ///
+    /// `fault_address` is only queried for `Status::Fault`.
+    pub fn from_code(code: u8, fault_address: impl FnOnce() -> u32) -> Option<Self> {
+        Some(match code {
+            255 => Status::Ok,
+            0 => Status::Halt,
+            1 => Status::Trap,
+            2 => Status::Fault(fault_address()),
+            3 => Status::Host,
+            4 => Status::OutOfGas,
+            _ => return None,
+        })
+    }
+
+    pub fn fault_address(&self) -> Option<u32> {
+        match self {
+            Status::Fault(address) => Some(*address),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -24,7 +54,7 @@ impl std::fmt::Display for Status {
                 Status::Ok => "ok",
                 Status::Halt => "halt",
                 Status::Trap => "panic",
-                Status::Fault => "page-fault",
+                Status::Fault(_) => "page-fault",
                 Status::Host => "host",
                 Status::OutOfGas => "out-of-gas",
             }
@@ -32,6 +62,24 @@ impl std::fmt::Display for Status {
     }
 }
 
+/// Instruction set width of the program.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, clap::ValueEnum)]
+pub enum Isa {
+    #[serde(rename = "32")]
+    #[value(name = "32")]
+    Bits32,
+    #[default]
+    #[serde(rename = "64")]
+    #[value(name = "64")]
+    Bits64,
+}
+
+impl Isa {
+    pub fn is_default(&self) -> bool {
+        *self == Isa::default()
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum ProgramContainer {
     Generic,
@@ -41,17 +89,27 @@ pub enum ProgramContainer {
     PolkaVM,
 }
 
-#[allow(dead_code)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum MemoryAccess {
     Readable,
     Writeable,
 }
 
+/// Full state of a PVM (excluding the program), see `PvmApi::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: [u64; NUMBER_OF_REGISTERS],
+    pub pc: u32,
+    pub gas: i64,
+    /// Accessible pages (by index) along with their contents.
+    pub pages: Vec<(u32, MemoryAccess, Vec<u8>)>,
+}
+
 #[derive(Debug)]
 pub enum Error {
     InvalidProgram,
     UnsupportedContainer,
+    UnsupportedIsa,
     Other(String),
     Wrap(Box<dyn std::error::Error + Sync + Send>),
 }
@@ -67,6 +125,7 @@ impl std::fmt::Display for Error {
         match self {
             Error::InvalidProgram => write!(f, "invalid program"),
             Error::UnsupportedContainer => write!(f, "unsupported container"),
+            Error::UnsupportedIsa => write!(f, "unsupported instruction set"),
             Error::Other(s) => write!(f, "Other: {s}"),
             Error::Wrap(e) => write!(f, "{e}"),
         }
@@ -81,8 +140,10 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub trait PvmApi {
     fn run(&mut self) -> Result<Status>;
 
-    #[allow(dead_code)]
-    // TODO [ToDr] Just to show what the API could look like for the PVM Debugger.
+    /// Execute a single instruction.
+    ///
+    /// Returns `Status::Ok` if the execution can be continued with either `step` or `run`.
+    /// PVMs that can't be stepped may execute the whole program instead.
     fn step(&mut self) -> Result<Status> {
         self.run()
     }
@@ -96,10 +157,48 @@ pub trait PvmApi {
     fn program_counter(&self) -> Option<u32>;
     fn set_next_program_counter(&mut self, pc: u32);
 
+    /// Called before the setup of every test case (see `runner::setup_testcase`).
+    fn begin_testcase(&mut self) {}
+
+    /// Select the instruction set width for programs loaded afterwards.
+    ///
+    /// PVMs which only support 64-bit programs can rely on the default implementation.
+    fn set_isa(&mut self, isa: Isa) -> Result<()> {
+        match isa {
+            Isa::Bits64 => Ok(()),
+            Isa::Bits32 => Err(Error::UnsupportedIsa),
+        }
+    }
+
+    /// Load a new program. This also resets the memory configuration (pages and contents).
     fn set_program(&mut self, code: &[u8], container: ProgramContainer) -> Result<()>;
 
+    /// Make the page with given index (i.e. `address / PAGE_SIZE`) accessible and zeroed.
     fn set_page(&mut self, page: u32, access: MemoryAccess);
-    #[allow(dead_code)]
     fn read_memory(&self, address: u32, out: &mut [u8]) -> Result<()>;
     fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<()>;
+
+    /// Additional information about the last execution (e.g. captured stderr) to include in failure reports.
+    fn diagnostics(&self) -> Option<String> {
+        None
+    }
+
+    /// Return and clear the disagreements recorded since the last call.
+    ///
+    /// Only collections of PVMs (see `collection::PvmApiCollection`) record them.
+    fn take_mismatches(&self) -> Vec<collection::Mismatch> {
+        vec![]
+    }
+
+    /// Capture the current state, so that it can be `restore`d later.
+    fn snapshot(&self) -> Result<Snapshot> {
+        Err(Error::Other("Snapshots are not supported.".into()))
+    }
+
+    /// Restore a state captured by `snapshot`. The execution continues from there on the next `run` or `step`.
+    ///
+    /// The currently loaded program is kept.
+    fn restore(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        Err(Error::Other("Snapshots are not supported.".into()))
+    }
 }
@@ -1,22 +1,58 @@
-use super::{
-    common::{InitialState, OutputState},
-    Error, ProgramContainer, PvmApi, Status,
-};
+use super::{common::InitialState, Error, Isa, MemoryAccess, ProgramContainer, PvmApi, Snapshot, Status, PAGE_SIZE};
 
+/// An instantiated program.
+struct Instance {
+    raw: polkavm::RawInstance,
+    /// Accessible pages (by index).
+    pages: Vec<(u32, MemoryAccess)>,
+    step_tracing: bool,
+    /// The last execution returned `Status::Ok`, so it can be continued.
+    live: bool,
+}
+
+impl std::fmt::Debug for Instance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Instance")
+            .field("step_tracing", &self.step_tracing)
+            .field("live", &self.live)
+            .field("pc", &self.raw.program_counter())
+            .field("gas", &self.raw.gas())
+            .finish()
+    }
+}
+
+/// Built-in polkavm interpreter.
+///
+/// Setters configure the initial state, unless the execution was interrupted by `step`,
+/// in which case they modify the live instance instead. Getters return the state of the
+/// last execution (or the initial state if nothing was executed yet).
 #[derive(Debug, Default)]
 pub struct PolkaVm {
     initial: InitialState,
-    output: OutputState,
+    instance: Option<Instance>,
+    /// Use the recompiler instead of the interpreter.
+    compiler: bool,
+}
+
+fn memory_error(e: polkavm::MemoryAccessError) -> Error {
+    Error::Other(format!("{e:?}"))
 }
 
 impl PolkaVm {
-    fn init_instance(&self) -> super::Result<polkavm::RawInstance> {
+    /// polkavm running programs with the recompiler backend (only available on some platforms).
+    pub fn compiler() -> Self {
+        Self {
+            compiler: true,
+            ..Default::default()
+        }
+    }
+
+    fn init_instance(&self, step_tracing: bool) -> super::Result<polkavm::RawInstance> {
         let parts = match self.initial.container {
             Some(ProgramContainer::Generic) => {
                 let mut parts = polkavm::ProgramParts::default();
                 parts.code_and_jump_table = self.initial.program.clone().into();
-                parts.is_64_bit = true;
-                // TODO [ToDr] setup memory
+                parts.is_64_bit = self.initial.isa == Isa::Bits64;
                 Ok(parts)
             }
             Some(ProgramContainer::PolkaVM) => polkavm::ProgramParts::from_bytes(self.initial.program.clone().into())
@@ -33,16 +69,26 @@ impl PolkaVm {
         })?;
 
         let mut config = polkavm::Config::new();
-        config.set_backend(Some(polkavm::BackendKind::Interpreter));
-        let engine = polkavm::Engine::new(&config).unwrap();
+        config.set_backend(Some(if self.compiler {
+            polkavm::BackendKind::Compiler
+        } else {
+            polkavm::BackendKind::Interpreter
+        }));
+        config.set_allow_dynamic_paging(true);
+        let engine = polkavm::Engine::new(&config).map_err(|e| Error::Other(format!("{e:?}")))?;
 
         let mut module_config = polkavm::ModuleConfig::default();
         module_config.set_strict(true);
         module_config.set_gas_metering(Some(polkavm::GasMeteringKind::Sync));
-        //module_config.set_step_tracing(true);
+        module_config.set_dynamic_paging(true);
+        module_config.set_page_size(PAGE_SIZE);
+        module_config.set_step_tracing(step_tracing);
 
-        let module = polkavm::Module::from_blob(&engine, &module_config, blob).unwrap();
-        let mut instance = module.instantiate().unwrap();
+        let module = polkavm::Module::from_blob(&engine, &module_config, blob).map_err(|e| {
+            log::error!("{:?}", e);
+            Error::InvalidProgram
+        })?;
+        let mut instance = module.instantiate().map_err(|e| Error::Other(format!("{e:?}")))?;
 
         instance.set_gas(self.initial.gas);
         instance.set_next_program_counter(polkavm::ProgramCounter(self.initial.pc));
@@ -50,63 +96,141 @@ impl PolkaVm {
             instance.set_reg(*reg, v);
         }
 
+        // map all pages first, so that initial data can be written to read-only pages too.
+        for (page, _) in &self.initial.pages {
+            instance
+                .zero_memory(page * PAGE_SIZE, PAGE_SIZE)
+                .map_err(memory_error)?;
+        }
+        for (address, data) in &self.initial.memory {
+            instance.write_memory(*address, data).map_err(memory_error)?;
+        }
+        for (page, access) in &self.initial.pages {
+            if *access == MemoryAccess::Readable {
+                instance
+                    .protect_memory(page * PAGE_SIZE, PAGE_SIZE)
+                    .map_err(memory_error)?;
+            }
+        }
+
         Ok(instance)
     }
-}
 
-impl PvmApi for PolkaVm {
-    fn run(&mut self) -> super::Result<Status> {
-        log::debug!("[polkavm] executing: {:?}", self);
+    fn live_instance(&mut self) -> Option<&mut polkavm::RawInstance> {
+        self.instance.as_mut().filter(|i| i.live).map(|i| &mut i.raw)
+    }
+
+    fn execute(&mut self, step: bool) -> super::Result<Status> {
         use polkavm::InterruptKind::*;
 
-        let mut instance = self.init_instance()?;
-        let status = instance.run();
-        let status = match status {
-            Ok(Finished) => Status::Halt,
-            Ok(Trap) => Status::Trap,
-            Ok(Ecalli(_call)) => Status::Host,
-            Ok(Segfault(_page)) => Status::Fault,
-            Ok(NotEnoughGas) => Status::OutOfGas,
-            Ok(Step) => Status::Ok,
-            Err(e) => {
-                log::error!("Error: {:?}", e);
-                Status::Trap
+        let (mut instance, fresh) = match self.instance.take().filter(|i| i.live) {
+            Some(instance) => (instance, false),
+            None => {
+                let raw = self.init_instance(step)?;
+                let instance = Instance {
+                    raw,
+                    pages: self.initial.pages.clone(),
+                    step_tracing: step,
+                    live: true,
+                };
+                (instance, true)
             }
         };
 
-        // copy results to `output` so they can be queried
-        self.output.gas = instance.gas();
-        self.output.pc = instance.program_counter().map(|x| x.0);
-        for (reg, out) in polkavm::Reg::ALL.iter().zip(&mut self.output.registers) {
-            *out = instance.reg(*reg);
-        }
+        // With step tracing polkavm interrupts *before* executing each instruction,
+        // so the very first interrupt of a fresh instance has to be skipped.
+        let mut skip_first = fresh && instance.step_tracing;
+        let status = loop {
+            match instance.raw.run() {
+                Ok(Step) if std::mem::take(&mut skip_first) || !step => continue,
+                Ok(Step) => break Status::Ok,
+                Ok(Finished) => break Status::Halt,
+                Ok(Trap) => break Status::Trap,
+                Ok(Ecalli(_call)) => break Status::Host,
+                Ok(Segfault(segfault)) => break Status::Fault(segfault.page_address),
+                Ok(NotEnoughGas) => break Status::OutOfGas,
+                Err(e) => {
+                    log::error!("Error: {:?}", e);
+                    break Status::Trap;
+                }
+            }
+        };
 
-        log::debug!("[polkavm] Complete with status {status}: {:?}", self.output);
+        instance.live = status == Status::Ok;
+        self.instance = Some(instance);
         Ok(status)
     }
+}
+
+impl PvmApi for PolkaVm {
+    fn run(&mut self) -> super::Result<Status> {
+        log::debug!("[polkavm] executing: {:?}", self);
+        let status = self.execute(false)?;
+        log::debug!(
+            "[polkavm] Complete with status {status}: {:?}, regs: {:?}",
+            self.instance,
+            self.registers()
+        );
+        Ok(status)
+    }
+
+    fn step(&mut self) -> super::Result<Status> {
+        self.execute(true)
+    }
 
     fn gas(&self) -> i64 {
-        self.output.gas
+        match &self.instance {
+            Some(instance) => instance.raw.gas(),
+            None => self.initial.gas,
+        }
     }
 
     fn set_gas(&mut self, gas: i64) {
-        self.initial.gas = gas;
+        match self.live_instance() {
+            Some(instance) => instance.set_gas(gas),
+            None => self.initial.gas = gas,
+        }
     }
 
     fn registers(&self) -> [u64; super::NUMBER_OF_REGISTERS] {
-        self.output.registers
+        let Some(instance) = &self.instance else {
+            return self.initial.registers;
+        };
+        let mut registers = [0u64; super::NUMBER_OF_REGISTERS];
+        for (reg, out) in polkavm::Reg::ALL.iter().zip(&mut registers) {
+            *out = instance.raw.reg(*reg);
+        }
+        registers
     }
 
     fn set_registers(&mut self, registers: &[u64; super::NUMBER_OF_REGISTERS]) {
-        self.initial.registers = registers.to_owned();
+        match self.live_instance() {
+            Some(instance) => {
+                for (reg, v) in polkavm::Reg::ALL.iter().zip(registers) {
+                    instance.set_reg(*reg, *v);
+                }
+            }
+            None => self.initial.registers = registers.to_owned(),
+        }
     }
 
     fn program_counter(&self) -> Option<u32> {
-        self.output.pc
+        match &self.instance {
+            Some(instance) => instance.raw.program_counter().map(|x| x.0),
+            None => Some(self.initial.pc),
+        }
     }
 
     fn set_next_program_counter(&mut self, pc: u32) {
-        self.initial.pc = pc;
+        match self.live_instance() {
+            Some(instance) => instance.set_next_program_counter(polkavm::ProgramCounter(pc)),
+            None => self.initial.pc = pc,
+        }
+    }
+
+    fn set_isa(&mut self, isa: Isa) -> super::Result<()> {
+        self.initial.isa = isa;
+        Ok(())
     }
 
     fn set_program(&mut self, code: &[u8], container: super::ProgramContainer) -> super::Result<()> {
@@ -114,20 +238,81 @@ impl PvmApi for PolkaVm {
             return Err(Error::UnsupportedContainer);
         }
         // TODO [ToDr] shall we parse the program here already?
+        self.instance = None;
         self.initial.program = code.to_vec();
         self.initial.container = Some(container);
+        self.initial.pages.clear();
+        self.initial.memory.clear();
         Ok(())
     }
 
-    fn set_page(&mut self, _page: u32, _access: super::MemoryAccess) {
-        todo!()
+    fn set_page(&mut self, page: u32, access: super::MemoryAccess) {
+        let Some(instance) = self.instance.as_mut().filter(|i| i.live) else {
+            return self.initial.set_page(page, access);
+        };
+        instance.pages.retain(|(p, _)| *p != page);
+        instance.pages.push((page, access));
+        let instance = &mut instance.raw;
+        let res = instance
+            .zero_memory(page * PAGE_SIZE, PAGE_SIZE)
+            .and_then(|_| match access {
+                MemoryAccess::Readable => instance.protect_memory(page * PAGE_SIZE, PAGE_SIZE),
+                MemoryAccess::Writeable => Ok(()),
+            });
+        if let Err(e) = res {
+            log::error!("[polkavm] Unable to set page {page}: {e:?}");
+        }
+    }
+
+    fn read_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        match &self.instance {
+            Some(instance) => instance
+                .raw
+                .read_memory_into(address, out)
+                .map(|_| ())
+                .map_err(memory_error),
+            None => self.initial.read_memory(address, out),
+        }
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> super::Result<()> {
+        match self.live_instance() {
+            Some(instance) => instance.write_memory(address, data).map_err(memory_error),
+            None => {
+                self.initial.memory.push((address, data.to_vec()));
+                Ok(())
+            }
+        }
     }
 
-    fn read_memory(&self, _address: u32, _out: &mut [u8]) -> super::Result<()> {
-        todo!()
+    fn snapshot(&self) -> super::Result<Snapshot> {
+        let pages = match &self.instance {
+            Some(instance) => &instance.pages,
+            None => &self.initial.pages,
+        };
+        let pages = pages
+            .iter()
+            .map(|(page, access)| {
+                let mut data = vec![0u8; PAGE_SIZE as usize];
+                self.read_memory(page * PAGE_SIZE, &mut data)?;
+                Ok((*page, *access, data))
+            })
+            .collect::<super::Result<_>>()?;
+
+        Ok(Snapshot {
+            registers: self.registers(),
+            pc: self
+                .program_counter()
+                .ok_or_else(|| Error::Other("Unknown program counter.".into()))?,
+            gas: self.gas(),
+            pages,
+        })
     }
 
-    fn write_memory(&mut self, _address: u32, _data: &[u8]) -> super::Result<()> {
-        todo!()
+    fn restore(&mut self, snapshot: &Snapshot) -> super::Result<()> {
+        // the next execution will start from a fresh instance initialized with the snapshot.
+        self.instance = None;
+        self.initial.restore(snapshot);
+        Ok(())
     }
 }
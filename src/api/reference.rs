@@ -0,0 +1,806 @@
+//! Reference interpreter following the gray paper (appendix A) as closely as possible.
+//!
+//! It's written for clarity rather than speed: every instruction is decoded right before it's
+//! executed and memory is a map of pages. It serves as a tie-breaker when other PVMs disagree.
+//!
+//! Every instruction costs 1 gas, as in the gray paper. If there is not enough gas left for an
+//! instruction, the execution stops there with `Status::OutOfGas` and the gas is left untouched.
+//! Note that polkavm charges whole basic blocks upfront, so the remaining gas differs when the
+//! execution stops in the middle of a block.
+
+use std::collections::BTreeMap;
+
+use super::{common::InitialState, Error, MemoryAccess, ProgramContainer, PvmApi, Snapshot, Status, PAGE_SIZE};
+use crate::{
+    instruction::{self, sign_extend_32, Args, Opcode, HALT_ADDRESS},
+    program::GenericProgram,
+};
+
+/// Dynamic jump targets are `(index + 1) * JUMP_ALIGNMENT` (gray paper `Z_A`).
+const JUMP_ALIGNMENT: u32 = 2;
+/// Accesses of memory below this address panic instead of causing page faults.
+const RESERVED_MEMORY: u32 = 1 << 16;
+const GAS_COST: i64 = 1;
+
+/// A loaded program along with its basic blocks.
+#[derive(Debug)]
+struct Program {
+    program: GenericProgram,
+    /// `true` for every pc which starts a basic block (gray paper `ϖ`).
+    block_starts: Vec<bool>,
+}
+
+impl Program {
+    fn new(program: GenericProgram) -> Self {
+        let mut block_starts = vec![false; program.code.len()];
+        let is_start = |pc: usize| program.bitmask.get(pc).copied().unwrap_or(false);
+        if is_start(0) {
+            block_starts[0] = true;
+        }
+        for pc in (0..program.code.len()).filter(|pc| is_start(*pc)) {
+            let is_terminator = instruction::opcode(program.code[pc]).is_some_and(|o| o.is_terminator());
+            let next = pc + 1 + program.skip(pc as u32) as usize;
+            if is_terminator && is_start(next) {
+                block_starts[next] = true;
+            }
+        }
+        Self { program, block_starts }
+    }
+
+    fn is_block_start(&self, pc: u32) -> bool {
+        self.block_starts.get(pc as usize).copied().unwrap_or(false)
+    }
+
+    /// Continue at `target` if `condition` holds (gray paper `branch`).
+    fn branch(&self, target: u64, condition: bool, next: u32) -> Result<u32, Status> {
+        match (condition, target as u32) {
+            (false, _) => Ok(next),
+            (true, target) if self.is_block_start(target) => Ok(target),
+            _ => Err(Status::Trap),
+        }
+    }
+
+    /// Jump through the jump table (gray paper `djump`).
+    fn djump(&self, address: u64) -> Result<u32, Status> {
+        let address = address as u32;
+        if address == HALT_ADDRESS {
+            return Err(Status::Halt);
+        }
+        let jump_table = &self.program.jump_table;
+        if address == 0 || !address.is_multiple_of(JUMP_ALIGNMENT) || address / JUMP_ALIGNMENT > jump_table.len() as u32
+        {
+            return Err(Status::Trap);
+        }
+        let target = jump_table[(address / JUMP_ALIGNMENT - 1) as usize];
+        if !self.is_block_start(target) {
+            return Err(Status::Trap);
+        }
+        Ok(target)
+    }
+}
+
+/// State of an execution.
+#[derive(Debug, Clone)]
+struct Machine {
+    registers: [u64; super::NUMBER_OF_REGISTERS],
+    pc: u32,
+    gas: i64,
+    /// Accessible pages (by index) and their contents.
+    pages: BTreeMap<u32, (MemoryAccess, Vec<u8>)>,
+    /// The last execution returned `Status::Ok`, so it can be continued.
+    live: bool,
+}
+
+/// Width in bytes and signedness of a load or store, from the suffix of its name.
+fn width(name: &str) -> (u32, bool) {
+    let suffix = name.rsplit('_').next().unwrap_or_default();
+    let bits: u32 = suffix[1..].parse().expect("loads and stores end with the width; qed");
+    (bits / 8, suffix.starts_with('i'))
+}
+
+impl Machine {
+    fn new(initial: &InitialState) -> super::Result<Self> {
+        let mut machine = Self {
+            registers: initial.registers,
+            pc: initial.pc,
+            gas: initial.gas,
+            pages: initial
+                .pages
+                .iter()
+                .map(|(page, access)| (*page, (*access, vec![0; PAGE_SIZE as usize])))
+                .collect(),
+            live: true,
+        };
+        for (address, data) in &initial.memory {
+            machine.write(*address, data)?;
+        }
+        Ok(machine)
+    }
+
+    /// Write memory regardless of the page access (i.e. from outside of the program).
+    fn write(&mut self, address: u32, data: &[u8]) -> super::Result<()> {
+        for (i, byte) in data.iter().enumerate() {
+            let address = address.wrapping_add(i as u32);
+            let (_, page) = self
+                .pages
+                .get_mut(&(address / PAGE_SIZE))
+                .ok_or_else(|| Error::Other(format!("Address {address:#x} is not accessible.")))?;
+            page[(address % PAGE_SIZE) as usize] = *byte;
+        }
+        Ok(())
+    }
+
+    fn read(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        for (i, byte) in out.iter_mut().enumerate() {
+            let address = address.wrapping_add(i as u32);
+            let (_, page) = self
+                .pages
+                .get(&(address / PAGE_SIZE))
+                .ok_or_else(|| Error::Other(format!("Address {address:#x} is not accessible.")))?;
+            *byte = page[(address % PAGE_SIZE) as usize];
+        }
+        Ok(())
+    }
+
+    /// Check that `len` bytes at `address` (wrapping around) can be accessed.
+    ///
+    /// Otherwise the execution panics if the lowest inaccessible address is reserved, or faults
+    /// at the page containing it.
+    fn check_access(&self, address: u32, len: u32, write: bool) -> Result<(), Status> {
+        let is_accessible = |address: u32| match self.pages.get(&(address / PAGE_SIZE)) {
+            Some((access, _)) => !write || *access == MemoryAccess::Writeable,
+            None => false,
+        };
+        let inaccessible = (0..len)
+            .map(|i| address.wrapping_add(i))
+            .filter(|a| !is_accessible(*a))
+            .min();
+        match inaccessible {
+            None => Ok(()),
+            Some(address) if address < RESERVED_MEMORY => Err(Status::Trap),
+            Some(address) => Err(Status::Fault(address / PAGE_SIZE * PAGE_SIZE)),
+        }
+    }
+
+    fn load(&self, address: u64, len: u32, signed: bool) -> Result<u64, Status> {
+        let address = address as u32;
+        self.check_access(address, len, false)?;
+        let mut bytes = [0; 8];
+        let len = len as usize;
+        self.read(address, &mut bytes[..len])
+            .expect("the access was checked; qed");
+        Ok(if signed {
+            instruction::sign_extend(&bytes[..len])
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    fn store(&mut self, address: u64, len: u32, value: u64) -> Result<(), Status> {
+        let address = address as u32;
+        self.check_access(address, len, true)?;
+        self.write(address, &value.to_le_bytes()[..len as usize])
+            .expect("the access was checked; qed");
+        Ok(())
+    }
+
+    /// Execute a single instruction.
+    fn step(&mut self, program: &Program) -> Status {
+        if self.gas < GAS_COST {
+            return Status::OutOfGas;
+        }
+        self.gas -= GAS_COST;
+
+        // positions which don't start an instruction (including the ones past the end) trap.
+        let skip = program.program.skip(self.pc);
+        let decoded = program
+            .program
+            .opcode_at(self.pc)
+            .and_then(|_| instruction::decode(&program.program.code, self.pc, skip));
+        let Some((opcode, args)) = decoded else {
+            return Status::Trap;
+        };
+        let next = self.pc.wrapping_add(1 + skip);
+        match self.execute(program, opcode, args, next) {
+            Ok(pc) => {
+                self.pc = pc;
+                Status::Ok
+            }
+            Err(status) => status,
+        }
+    }
+
+    /// Execute the instruction, returning the pc to continue at or the status of the termination.
+    ///
+    /// Register names follow the gray paper: `a` and `b` are the values of `regs[0]` and `regs[1]`
+    /// (for two-register instructions `regs[0]` is the destination and `b` the source), `x` and
+    /// `y` are the immediates (or jump targets).
+    fn execute(&mut self, program: &Program, opcode: &Opcode, args: Args, next: u32) -> Result<u32, Status> {
+        let [ra, rb, rd] = args.regs.map(usize::from);
+        let (a, b) = (self.registers[ra], self.registers[rb]);
+        let [x, y] = args.imms;
+        let (a32, b32, x32) = (a as u32, b as u32, x as u32);
+        let reg = &mut self.registers;
+
+        match opcode.name {
+            "trap" => return Err(Status::Trap),
+            "fallthrough" => {}
+            "ecalli" => return Err(Status::Host),
+            "load_imm_64" | "load_imm" => reg[ra] = x,
+            "jump" => return program.branch(x, true, next),
+            "jump_ind" => return program.djump(a.wrapping_add(x)),
+            "load_imm_jump" => {
+                reg[ra] = x;
+                return program.branch(y, true, next);
+            }
+            "load_imm_jump_ind" => {
+                let target = b.wrapping_add(y);
+                reg[ra] = x;
+                return program.djump(target);
+            }
+
+            // memory accesses: `load_` loads from `x`, `load_ind_` from `b + x`, stores likewise.
+            name if name.starts_with("load_ind_") => {
+                let (len, signed) = width(name);
+                let value = self.load(b.wrapping_add(x), len, signed)?;
+                self.registers[ra] = value;
+            }
+            name if name.starts_with("load_") => {
+                let (len, signed) = width(name);
+                let value = self.load(x, len, signed)?;
+                self.registers[ra] = value;
+            }
+            name if name.starts_with("store_imm_ind_") => self.store(a.wrapping_add(x), width(name).0, y)?,
+            name if name.starts_with("store_imm_") => self.store(x, width(name).0, y)?,
+            name if name.starts_with("store_ind_") => self.store(b.wrapping_add(x), width(name).0, a)?,
+            name if name.starts_with("store_") => self.store(x, width(name).0, a)?,
+
+            "branch_eq_imm" => return program.branch(y, a == x, next),
+            "branch_ne_imm" => return program.branch(y, a != x, next),
+            "branch_lt_u_imm" => return program.branch(y, a < x, next),
+            "branch_le_u_imm" => return program.branch(y, a <= x, next),
+            "branch_ge_u_imm" => return program.branch(y, a >= x, next),
+            "branch_gt_u_imm" => return program.branch(y, a > x, next),
+            "branch_lt_s_imm" => return program.branch(y, (a as i64) < (x as i64), next),
+            "branch_le_s_imm" => return program.branch(y, (a as i64) <= (x as i64), next),
+            "branch_ge_s_imm" => return program.branch(y, (a as i64) >= (x as i64), next),
+            "branch_gt_s_imm" => return program.branch(y, (a as i64) > (x as i64), next),
+            "branch_eq" => return program.branch(x, a == b, next),
+            "branch_ne" => return program.branch(x, a != b, next),
+            "branch_lt_u" => return program.branch(x, a < b, next),
+            "branch_lt_s" => return program.branch(x, (a as i64) < (b as i64), next),
+            "branch_ge_u" => return program.branch(x, a >= b, next),
+            "branch_ge_s" => return program.branch(x, (a as i64) >= (b as i64), next),
+
+            // two registers: `regs[0]` is the destination, `b` the source.
+            "move_reg" => reg[ra] = b,
+            // the heap is not part of the test case format, so there is nothing to allocate from.
+            "sbrk" => return Err(Status::Trap),
+            "count_set_bits_64" => reg[ra] = u64::from(b.count_ones()),
+            "count_set_bits_32" => reg[ra] = u64::from(b32.count_ones()),
+            "leading_zero_bits_64" => reg[ra] = u64::from(b.leading_zeros()),
+            "leading_zero_bits_32" => reg[ra] = u64::from(b32.leading_zeros()),
+            "trailing_zero_bits_64" => reg[ra] = u64::from(b.trailing_zeros()),
+            "trailing_zero_bits_32" => reg[ra] = u64::from(b32.trailing_zeros()),
+            "sign_extend_8" => reg[ra] = b as u8 as i8 as i64 as u64,
+            "sign_extend_16" => reg[ra] = b as u16 as i16 as i64 as u64,
+            "zero_extend_16" => reg[ra] = u64::from(b as u16),
+            "reverse_bytes" => reg[ra] = b.swap_bytes(),
+
+            // two registers and an immediate: `regs[0] = b op x`.
+            "add_imm_32" => reg[ra] = sign_extend_32(b32.wrapping_add(x32)),
+            "and_imm" => reg[ra] = b & x,
+            "xor_imm" => reg[ra] = b ^ x,
+            "or_imm" => reg[ra] = b | x,
+            "mul_imm_32" => reg[ra] = sign_extend_32(b32.wrapping_mul(x32)),
+            "set_lt_u_imm" => reg[ra] = u64::from(b < x),
+            "set_lt_s_imm" => reg[ra] = u64::from((b as i64) < (x as i64)),
+            "shlo_l_imm_32" => reg[ra] = sign_extend_32(b32 << (x % 32)),
+            "shlo_r_imm_32" => reg[ra] = sign_extend_32(b32 >> (x % 32)),
+            "shar_r_imm_32" => reg[ra] = sign_extend_32(((b32 as i32) >> (x % 32)) as u32),
+            "neg_add_imm_32" => reg[ra] = sign_extend_32(x32.wrapping_sub(b32)),
+            "set_gt_u_imm" => reg[ra] = u64::from(b > x),
+            "set_gt_s_imm" => reg[ra] = u64::from((b as i64) > (x as i64)),
+            "shlo_l_imm_alt_32" => reg[ra] = sign_extend_32(x32 << (b % 32)),
+            "shlo_r_imm_alt_32" => reg[ra] = sign_extend_32(x32 >> (b % 32)),
+            "shar_r_imm_alt_32" => reg[ra] = sign_extend_32(((x32 as i32) >> (b % 32)) as u32),
+            "cmov_iz_imm" if b == 0 => reg[ra] = x,
+            "cmov_nz_imm" if b != 0 => reg[ra] = x,
+            "cmov_iz_imm" | "cmov_nz_imm" => {}
+            "add_imm_64" => reg[ra] = b.wrapping_add(x),
+            "mul_imm_64" => reg[ra] = b.wrapping_mul(x),
+            "shlo_l_imm_64" => reg[ra] = b << (x % 64),
+            "shlo_r_imm_64" => reg[ra] = b >> (x % 64),
+            "shar_r_imm_64" => reg[ra] = ((b as i64) >> (x % 64)) as u64,
+            "neg_add_imm_64" => reg[ra] = x.wrapping_sub(b),
+            "shlo_l_imm_alt_64" => reg[ra] = x << (b % 64),
+            "shlo_r_imm_alt_64" => reg[ra] = x >> (b % 64),
+            "shar_r_imm_alt_64" => reg[ra] = ((x as i64) >> (b % 64)) as u64,
+            "rot_r_64_imm" => reg[ra] = b.rotate_right((x % 64) as u32),
+            "rot_r_64_imm_alt" => reg[ra] = x.rotate_right((b % 64) as u32),
+            "rot_r_32_imm" => reg[ra] = sign_extend_32(b32.rotate_right(x32 % 32)),
+            "rot_r_32_imm_alt" => reg[ra] = sign_extend_32(x32.rotate_right(b32 % 32)),
+
+            // three registers: `regs[2] = a op b`.
+            "add_32" => reg[rd] = sign_extend_32(a32.wrapping_add(b32)),
+            "sub_32" => reg[rd] = sign_extend_32(a32.wrapping_sub(b32)),
+            "mul_32" => reg[rd] = sign_extend_32(a32.wrapping_mul(b32)),
+            "div_u_32" if b32 == 0 => reg[rd] = u64::MAX,
+            "div_u_32" => reg[rd] = sign_extend_32(a32 / b32),
+            "div_s_32" if b32 == 0 => reg[rd] = u64::MAX,
+            "div_s_32" => reg[rd] = (a32 as i32).wrapping_div(b32 as i32) as i64 as u64,
+            "rem_u_32" if b32 == 0 => reg[rd] = sign_extend_32(a32),
+            "rem_u_32" => reg[rd] = sign_extend_32(a32 % b32),
+            "rem_s_32" if b32 == 0 => reg[rd] = sign_extend_32(a32),
+            "rem_s_32" => reg[rd] = (a32 as i32).wrapping_rem(b32 as i32) as i64 as u64,
+            "shlo_l_32" => reg[rd] = sign_extend_32(a32 << (b % 32)),
+            "shlo_r_32" => reg[rd] = sign_extend_32(a32 >> (b % 32)),
+            "shar_r_32" => reg[rd] = sign_extend_32(((a32 as i32) >> (b % 32)) as u32),
+            "add_64" => reg[rd] = a.wrapping_add(b),
+            "sub_64" => reg[rd] = a.wrapping_sub(b),
+            "mul_64" => reg[rd] = a.wrapping_mul(b),
+            "div_u_64" if b == 0 => reg[rd] = u64::MAX,
+            "div_u_64" => reg[rd] = a / b,
+            "div_s_64" if b == 0 => reg[rd] = u64::MAX,
+            "div_s_64" => reg[rd] = (a as i64).wrapping_div(b as i64) as u64,
+            "rem_u_64" if b == 0 => reg[rd] = a,
+            "rem_u_64" => reg[rd] = a % b,
+            "rem_s_64" if b == 0 => reg[rd] = a,
+            "rem_s_64" => reg[rd] = (a as i64).wrapping_rem(b as i64) as u64,
+            "shlo_l_64" => reg[rd] = a << (b % 64),
+            "shlo_r_64" => reg[rd] = a >> (b % 64),
+            "shar_r_64" => reg[rd] = ((a as i64) >> (b % 64)) as u64,
+            "and" => reg[rd] = a & b,
+            "xor" => reg[rd] = a ^ b,
+            "or" => reg[rd] = a | b,
+            "mul_upper_s_s" => reg[rd] = ((i128::from(a as i64) * i128::from(b as i64)) >> 64) as u64,
+            "mul_upper_u_u" => reg[rd] = ((u128::from(a) * u128::from(b)) >> 64) as u64,
+            "mul_upper_s_u" => reg[rd] = ((i128::from(a as i64) * i128::from(b)) >> 64) as u64,
+            "set_lt_u" => reg[rd] = u64::from(a < b),
+            "set_lt_s" => reg[rd] = u64::from((a as i64) < (b as i64)),
+            "cmov_iz" if b == 0 => reg[rd] = a,
+            "cmov_nz" if b != 0 => reg[rd] = a,
+            "cmov_iz" | "cmov_nz" => {}
+            "rot_l_64" => reg[rd] = a.rotate_left((b % 64) as u32),
+            "rot_l_32" => reg[rd] = sign_extend_32(a32.rotate_left(b32 % 32)),
+            "rot_r_64" => reg[rd] = a.rotate_right((b % 64) as u32),
+            "rot_r_32" => reg[rd] = sign_extend_32(a32.rotate_right(b32 % 32)),
+            "and_inv" => reg[rd] = a & !b,
+            "or_inv" => reg[rd] = a | !b,
+            "xnor" => reg[rd] = !(a ^ b),
+            "max" => reg[rd] = (a as i64).max(b as i64) as u64,
+            "max_u" => reg[rd] = a.max(b),
+            "min" => reg[rd] = (a as i64).min(b as i64) as u64,
+            "min_u" => reg[rd] = a.min(b),
+
+            name => unreachable!("all opcodes are handled, but {name} is missing"),
+        }
+        Ok(next)
+    }
+}
+
+/// Built-in reference interpreter.
+///
+/// Like `PolkaVm`, setters configure the initial state unless the execution was interrupted by
+/// `step`, and getters return the state of the last execution.
+#[derive(Debug, Default)]
+pub struct Reference {
+    initial: InitialState,
+    program: Option<Program>,
+    machine: Option<Machine>,
+}
+
+impl Reference {
+    fn live_machine(&mut self) -> Option<&mut Machine> {
+        self.machine.as_mut().filter(|m| m.live)
+    }
+
+    fn execute(&mut self, step: bool) -> super::Result<Status> {
+        let program = self.program.as_ref().ok_or(Error::InvalidProgram)?;
+        let mut machine = match self.machine.take().filter(|m| m.live) {
+            Some(machine) => machine,
+            None => Machine::new(&self.initial)?,
+        };
+
+        let status = loop {
+            match machine.step(program) {
+                Status::Ok if !step => continue,
+                status => break status,
+            }
+        };
+
+        machine.live = status == Status::Ok;
+        self.machine = Some(machine);
+        Ok(status)
+    }
+}
+
+impl PvmApi for Reference {
+    fn run(&mut self) -> super::Result<Status> {
+        self.execute(false)
+    }
+
+    fn step(&mut self) -> super::Result<Status> {
+        self.execute(true)
+    }
+
+    fn gas(&self) -> i64 {
+        match &self.machine {
+            Some(machine) => machine.gas,
+            None => self.initial.gas,
+        }
+    }
+
+    fn set_gas(&mut self, gas: i64) {
+        match self.live_machine() {
+            Some(machine) => machine.gas = gas,
+            None => self.initial.gas = gas,
+        }
+    }
+
+    fn registers(&self) -> [u64; super::NUMBER_OF_REGISTERS] {
+        match &self.machine {
+            Some(machine) => machine.registers,
+            None => self.initial.registers,
+        }
+    }
+
+    fn set_registers(&mut self, registers: &[u64; super::NUMBER_OF_REGISTERS]) {
+        match self.live_machine() {
+            Some(machine) => machine.registers = *registers,
+            None => self.initial.registers = *registers,
+        }
+    }
+
+    fn program_counter(&self) -> Option<u32> {
+        match &self.machine {
+            Some(machine) => Some(machine.pc),
+            None => Some(self.initial.pc),
+        }
+    }
+
+    fn set_next_program_counter(&mut self, pc: u32) {
+        match self.live_machine() {
+            Some(machine) => machine.pc = pc,
+            None => self.initial.pc = pc,
+        }
+    }
+
+    fn set_program(&mut self, code: &[u8], container: ProgramContainer) -> super::Result<()> {
+        let ProgramContainer::Generic = container else {
+            return Err(Error::UnsupportedContainer);
+        };
+        let program = GenericProgram::parse(code).ok_or(Error::InvalidProgram)?;
+        self.program = Some(Program::new(program));
+        self.machine = None;
+        self.initial.pages.clear();
+        self.initial.memory.clear();
+        Ok(())
+    }
+
+    fn set_page(&mut self, page: u32, access: MemoryAccess) {
+        match self.live_machine() {
+            Some(machine) => {
+                machine.pages.insert(page, (access, vec![0; PAGE_SIZE as usize]));
+            }
+            None => self.initial.set_page(page, access),
+        }
+    }
+
+    fn read_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        match &self.machine {
+            Some(machine) => machine.read(address, out),
+            None => self.initial.read_memory(address, out),
+        }
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> super::Result<()> {
+        match self.live_machine() {
+            Some(machine) => machine.write(address, data),
+            None => {
+                self.initial.memory.push((address, data.to_vec()));
+                Ok(())
+            }
+        }
+    }
+
+    fn snapshot(&self) -> super::Result<Snapshot> {
+        let machine = match &self.machine {
+            Some(machine) => machine.clone(),
+            None => Machine::new(&self.initial)?,
+        };
+        Ok(Snapshot {
+            registers: machine.registers,
+            pc: machine.pc,
+            gas: machine.gas,
+            pages: machine
+                .pages
+                .into_iter()
+                .map(|(page, (access, data))| (page, access, data))
+                .collect(),
+        })
+    }
+
+    fn restore(&mut self, snapshot: &Snapshot) -> super::Result<()> {
+        self.machine = None;
+        self.initial.restore(snapshot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Expected values are computed by hand from the gray paper (appendix A.5).
+
+    use super::*;
+    use crate::{api::NUMBER_OF_REGISTERS, instruction::OPCODES};
+
+    const A: u8 = 7;
+    const B: u8 = 8;
+    const D: u8 = 9;
+    /// A writable page, followed by a read-only one.
+    const MEMORY: u32 = 0x20000;
+
+    type Code = [(&'static str, Args)];
+
+    fn args(regs: [u8; 3], imms: [u64; 2]) -> Args {
+        Args { regs, imms }
+    }
+
+    const TRAP: (&str, Args) = (
+        "trap",
+        Args {
+            regs: [0; 3],
+            imms: [0; 2],
+        },
+    );
+    const FALLTHROUGH: (&str, Args) = (
+        "fallthrough",
+        Args {
+            regs: [0; 3],
+            imms: [0; 2],
+        },
+    );
+
+    fn op(name: &str) -> &'static Opcode {
+        OPCODES.iter().find(|o| o.name == name).expect("known opcode")
+    }
+
+    /// The pc following the instructions (jump targets don't change the length).
+    fn len(code: &Code) -> u64 {
+        code.iter()
+            .map(|(name, args)| instruction::encode(op(name), args, 0).len() as u64)
+            .sum()
+    }
+
+    /// Run the code with given registers, returning the status and the interpreter.
+    fn run(code: &Code, jump_table: &[u32], registers: &[(u8, u64)]) -> (Status, Reference) {
+        let mut program = GenericProgram::default();
+        for (name, args) in code {
+            program.push(op(name), args);
+        }
+        program.jump_table = jump_table.to_vec();
+
+        let mut pvm = Reference::default();
+        pvm.set_program(&program.encode(), ProgramContainer::Generic).unwrap();
+        let mut regs = [0; NUMBER_OF_REGISTERS];
+        for (register, value) in registers {
+            regs[*register as usize] = *value;
+        }
+        pvm.set_registers(&regs);
+        pvm.set_gas(100);
+        pvm.set_page(MEMORY / PAGE_SIZE, MemoryAccess::Writeable);
+        pvm.set_page(MEMORY / PAGE_SIZE + 1, MemoryAccess::Readable);
+        pvm.write_memory(MEMORY, &[0x80, 0xff, 0x01, 0x02]).unwrap();
+        pvm.write_memory(MEMORY + PAGE_SIZE, &[0x11, 0x22, 0x33, 0x44]).unwrap();
+        let status = pvm.run().unwrap();
+        (status, pvm)
+    }
+
+    fn memory(pvm: &Reference, address: u32, len: usize) -> Vec<u8> {
+        let mut out = vec![0; len];
+        pvm.read_memory(address, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn trap_and_fallthrough() {
+        let (status, pvm) = run(&[FALLTHROUGH, TRAP], &[], &[]);
+        assert_eq!(status, Status::Trap);
+        assert_eq!(pvm.program_counter(), Some(1));
+        assert_eq!(pvm.gas(), 98);
+    }
+
+    #[test]
+    fn out_of_gas_leaves_gas_untouched() {
+        let mut program = GenericProgram::default();
+        program.push(op("fallthrough"), &Args::default());
+        let mut pvm = Reference::default();
+        pvm.set_program(&program.encode(), ProgramContainer::Generic).unwrap();
+        pvm.set_gas(0);
+        assert_eq!(pvm.run().unwrap(), Status::OutOfGas);
+        assert_eq!(pvm.gas(), 0);
+        assert_eq!(pvm.program_counter(), Some(0));
+    }
+
+    #[test]
+    fn running_past_the_end_traps() {
+        let (status, pvm) = run(&[FALLTHROUGH], &[], &[]);
+        assert_eq!(status, Status::Trap);
+        assert_eq!(pvm.program_counter(), Some(1));
+    }
+
+    #[test]
+    fn jump_to_block_start() {
+        let jump = |target| ("jump", args([0; 3], [target, 0]));
+        let target = len(&[jump(0), TRAP]);
+        let (status, pvm) = run(&[jump(target), TRAP, TRAP], &[], &[]);
+        assert_eq!(status, Status::Trap);
+        assert_eq!(pvm.program_counter(), Some(target as u32));
+    }
+
+    #[test]
+    fn jump_into_the_middle_of_a_block_traps() {
+        // pc 2 is in the middle of the jump instruction.
+        let (status, pvm) = run(&[("jump", args([0; 3], [2, 0])), TRAP], &[], &[]);
+        assert_eq!(status, Status::Trap);
+        assert_eq!(pvm.program_counter(), Some(0));
+    }
+
+    #[test]
+    fn branch_taken_and_not_taken() {
+        let branch = |target| ("branch_eq_imm", args([A, 0, 0], [5, target]));
+        let target = len(&[branch(0), TRAP]);
+        let code = [branch(target), TRAP, TRAP];
+
+        let (status, pvm) = run(&code, &[], &[(A, 5)]);
+        assert_eq!((status, pvm.program_counter()), (Status::Trap, Some(target as u32)));
+        let (status, pvm) = run(&code, &[], &[(A, 6)]);
+        assert_eq!((status, pvm.program_counter()), (Status::Trap, Some(target as u32 - 1)));
+    }
+
+    #[test]
+    fn signed_and_unsigned_branches() {
+        let cases = [
+            ("branch_lt_u_imm", u64::MAX, 1, false),
+            ("branch_lt_s_imm", u64::MAX, 1, true),
+            ("branch_ge_s_imm", 1, u64::MAX, true),
+            ("branch_gt_u_imm", u64::MAX, 1, true),
+            ("branch_le_s_imm", 1, u64::MAX, false),
+        ];
+        for (name, a, x, taken) in cases {
+            let branch = |target| (name, args([A, 0, 0], [x, target]));
+            let target = len(&[branch(0), TRAP]);
+            let (_, pvm) = run(&[branch(target), TRAP, TRAP], &[], &[(A, a)]);
+            let expected = if taken { target } else { target - 1 };
+            assert_eq!(pvm.program_counter(), Some(expected as u32), "{name}");
+        }
+
+        let branch = |target| ("branch_lt_s", args([A, B, 0], [target, 0]));
+        let target = len(&[branch(0), TRAP]);
+        let (_, pvm) = run(&[branch(target), TRAP, TRAP], &[], &[(A, i64::MIN as u64), (B, 0)]);
+        assert_eq!(pvm.program_counter(), Some(target as u32));
+    }
+
+    #[test]
+    fn load_imm_jump() {
+        let jump = |target| ("load_imm_jump", args([A, 0, 0], [42, target]));
+        let target = len(&[jump(0), TRAP]);
+        let (_, pvm) = run(&[jump(target), TRAP, TRAP], &[], &[]);
+        assert_eq!(pvm.registers()[A as usize], 42);
+        assert_eq!(pvm.program_counter(), Some(target as u32));
+    }
+
+    #[test]
+    fn jump_ind_through_jump_table() {
+        let jump = ("jump_ind", args([A, 0, 0], [2, 0]));
+        let target = len(&[jump, TRAP]) as u32;
+        let code = [jump, TRAP, TRAP];
+
+        // `djump(a + x)` with `a + x = 4` is the second entry.
+        let (status, pvm) = run(&code, &[0, target], &[(A, 2)]);
+        assert_eq!((status, pvm.program_counter()), (Status::Trap, Some(target)));
+        // misaligned, zero and past the table.
+        for a in [1, u64::MAX - 1, 4] {
+            let (status, pvm) = run(&code, &[0, target], &[(A, a)]);
+            assert_eq!((status, pvm.program_counter()), (Status::Trap, Some(0)), "{a}");
+        }
+        let (status, _) = run(&code, &[], &[(A, u64::from(HALT_ADDRESS) - 2)]);
+        assert_eq!(status, Status::Halt);
+    }
+
+    #[test]
+    fn load_imm_jump_ind_sets_x_and_jumps_to_b_plus_y() {
+        // `φ'A = νX` and `djump(φB + νY)`.
+        let jump = ("load_imm_jump_ind", args([A, B, 0], [42, 2]));
+        let target = len(&[jump, TRAP]) as u32;
+        let (status, pvm) = run(&[jump, TRAP, TRAP], &[target], &[(B, 0)]);
+        assert_eq!((status, pvm.program_counter()), (Status::Trap, Some(target)));
+        assert_eq!(pvm.registers()[A as usize], 42);
+
+        // the target is computed before the register is set.
+        let jump = ("load_imm_jump_ind", args([A, A, 0], [0, 0]));
+        let (status, _) = run(&[jump, TRAP], &[], &[(A, u64::from(HALT_ADDRESS))]);
+        assert_eq!(status, Status::Halt);
+    }
+
+    #[test]
+    fn loads_are_zero_or_sign_extended() {
+        let address = u64::from(MEMORY);
+        let cases = [
+            ("load_u8", 0x80),
+            ("load_i8", 0xffff_ffff_ffff_ff80),
+            ("load_u16", 0xff80),
+            ("load_i16", 0xffff_ffff_ffff_ff80),
+            ("load_u32", 0x0201_ff80),
+            ("load_i32", 0x0201_ff80),
+            ("load_u64", 0x0201_ff80),
+        ];
+        for (name, expected) in cases {
+            let (status, pvm) = run(&[(name, args([A, 0, 0], [address, 0])), TRAP], &[], &[]);
+            assert_eq!(status, Status::Trap, "{name}");
+            assert_eq!(pvm.registers()[A as usize], expected, "{name}");
+        }
+
+        let load = ("load_ind_i16", args([A, B, 0], [u64::MAX - 1, 0]));
+        let (_, pvm) = run(&[load, TRAP], &[], &[(B, address + 2)]);
+        assert_eq!(pvm.registers()[A as usize], 0xffff_ffff_ffff_ff80);
+    }
+
+    #[test]
+    fn stores_write_little_endian() {
+        let address = u64::from(MEMORY);
+        let store = ("store_ind_u32", args([A, B, 0], [4, 0]));
+        let (_, pvm) = run(&[store, TRAP], &[], &[(A, 0x1122_3344_5566_7788), (B, address)]);
+        assert_eq!(memory(&pvm, MEMORY + 4, 4), [0x88, 0x77, 0x66, 0x55]);
+
+        let store = ("store_imm_ind_u16", args([A, 0, 0], [8, u64::MAX]));
+        let (_, pvm) = run(&[store, TRAP], &[], &[(A, address)]);
+        assert_eq!(memory(&pvm, MEMORY + 8, 3), [0xff, 0xff, 0]);
+
+        let store = ("store_imm_u8", args([0; 3], [address, 0x1ff]));
+        let (_, pvm) = run(&[store, TRAP], &[], &[]);
+        assert_eq!(memory(&pvm, MEMORY, 2), [0xff, 0xff]);
+    }
+
+    #[test]
+    fn inaccessible_memory_faults_or_panics() {
+        let page = |n: u32| MEMORY + n * PAGE_SIZE;
+        // crossing from the read-only page into an unmapped one faults at the unmapped page.
+        let load = ("load_u32", args([A, 0, 0], [u64::from(page(2) - 2), 0]));
+        let (status, pvm) = run(&[load, TRAP], &[], &[]);
+        assert_eq!((status, pvm.program_counter()), (Status::Fault(page(2)), Some(0)));
+
+        let store = ("store_u16", args([A, 0, 0], [u64::from(page(1)), 0]));
+        let (status, pvm) = run(&[store, TRAP], &[], &[]);
+        assert_eq!(status, Status::Fault(page(1)));
+        assert_eq!(memory(&pvm, page(1), 2), [0x11, 0x22]);
+
+        // the first 64 KiB are reserved.
+        let load = ("load_u8", args([A, 0, 0], [0x100, 0]));
+        let (status, _) = run(&[load, TRAP], &[], &[]);
+        assert_eq!(status, Status::Trap);
+    }
+
+    #[test]
+    fn arithmetic_edge_cases() {
+        let min = i64::MIN as u64;
+        let cases = [
+            ("add_32", u64::from(u32::MAX), 1, 0),
+            ("add_32", 0x7fff_ffff, 1, 0xffff_ffff_8000_0000),
+            ("div_u_64", 1, 0, u64::MAX),
+            ("div_s_64", min, u64::MAX, min),
+            ("rem_s_64", min, u64::MAX, 0),
+            ("rem_u_32", 0x1_0000_0005, 0, 5),
+            ("div_s_32", i32::MIN as u32 as u64, u64::MAX, i32::MIN as i64 as u64),
+            ("shlo_l_64", 1, 65, 2),
+            ("shar_r_32", 0x8000_0000, 31, u64::MAX),
+            ("mul_upper_s_u", u64::MAX, 2, u64::MAX),
+            ("cmov_nz", 7, 1, 7),
+        ];
+        for (name, a, b, expected) in cases {
+            let (_, pvm) = run(&[(name, args([A, B, D], [0; 2])), TRAP], &[], &[(A, a), (B, b)]);
+            assert_eq!(pvm.registers()[D as usize], expected, "{name}({a:#x}, {b:#x})");
+        }
+    }
+}
@@ -1,32 +1,200 @@
-use std::io::{BufRead, BufReader};
+use std::{
+    io::{BufRead, BufReader},
+    sync::{Arc, Mutex},
+};
 
-use crate::json::TestcaseJson;
+use parity_scale_codec::{Decode, DecodeAll, Encode};
 
-use super::{common::OutputState, ProgramContainer, PvmApi, Status};
+use crate::json::{MemoryChunk, Page, TestcaseJson};
+
+use super::{
+    common::{copy_overlap, read_chunks, read_initial_memory, OutputState},
+    Isa, MemoryAccess, ProgramContainer, PvmApi, Snapshot, Status, PAGE_SIZE,
+};
+
+/// Message framing used to talk to the stdin PVM.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Framing {
+    /// `TestcaseJson` serialized as JSON, messages terminated with an empty line.
+    #[default]
+    Json,
+    /// `BINARY_VERSION` followed by the SCALE-encoded `BinaryTestcase`, each message prefixed
+    /// with its length as little-endian `u32`.
+    Binary,
+}
+
+/// Status of a response to a test case the stdin PVM couldn't execute (e.g. an unsupported program).
+///
+/// Unlike the statuses of `Status`, it's never the result of an execution, so it's reported as an error.
+pub const ERROR_STATUS: &str = "error";
+
+/// Version of the binary framing, the first byte of every message (after the length).
+pub const BINARY_VERSION: u8 = 1;
+
+/// Maximum length of a message in the binary framing, larger length prefixes are rejected
+/// before allocating the buffer.
+pub const MAX_MESSAGE_LEN: u32 = 256 * 1024 * 1024;
+
+/// A test case in the binary framing.
+///
+/// The layout is independent of `TestcaseJson`, changing it requires bumping `BINARY_VERSION`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct BinaryTestcase {
+    pub name: String,
+    /// 32 or 64.
+    pub isa: u8,
+    pub initial_regs: [u64; super::NUMBER_OF_REGISTERS],
+    pub initial_pc: u32,
+    /// Address, length and whether the pages are writable.
+    pub initial_page_map: Vec<(u32, u32, bool)>,
+    /// Address and contents.
+    pub initial_memory: Vec<(u32, Vec<u8>)>,
+    pub initial_gas: i64,
+    pub program: Vec<u8>,
+    pub expected_status: String,
+    pub expected_page_fault_address: Option<u32>,
+    pub expected_regs: [u64; super::NUMBER_OF_REGISTERS],
+    pub expected_pc: u32,
+    pub expected_memory: Vec<(u32, Vec<u8>)>,
+    pub expected_gas: i64,
+}
+
+impl From<&TestcaseJson> for BinaryTestcase {
+    fn from(json: &TestcaseJson) -> Self {
+        let chunks = |memory: &[MemoryChunk]| memory.iter().map(|c| (c.address, c.contents.clone())).collect();
+        let mut expected_regs = [0; super::NUMBER_OF_REGISTERS];
+        for (out, reg) in expected_regs.iter_mut().zip(&json.expected_regs) {
+            *out = *reg;
+        }
+        Self {
+            name: json.name.clone(),
+            isa: match json.isa {
+                Isa::Bits32 => 32,
+                Isa::Bits64 => 64,
+            },
+            initial_regs: json.initial_regs,
+            initial_pc: json.initial_pc,
+            initial_page_map: json
+                .initial_page_map
+                .iter()
+                .map(|p| (p.address, p.length, p.is_writable))
+                .collect(),
+            initial_memory: chunks(&json.initial_memory),
+            initial_gas: json.initial_gas,
+            program: json.program.clone(),
+            expected_status: json.expected_status.clone(),
+            expected_page_fault_address: json.expected_page_fault_address,
+            expected_regs,
+            expected_pc: json.expected_pc,
+            expected_memory: chunks(&json.expected_memory),
+            expected_gas: json.expected_gas,
+        }
+    }
+}
+
+impl TryFrom<BinaryTestcase> for TestcaseJson {
+    type Error = String;
+
+    fn try_from(binary: BinaryTestcase) -> Result<Self, Self::Error> {
+        let chunks = |memory: Vec<(u32, Vec<u8>)>| {
+            memory
+                .into_iter()
+                .map(|(address, contents)| MemoryChunk { address, contents })
+                .collect()
+        };
+        Ok(Self {
+            name: binary.name,
+            isa: match binary.isa {
+                32 => Isa::Bits32,
+                64 => Isa::Bits64,
+                isa => return Err(format!("Invalid ISA: {isa}")),
+            },
+            initial_regs: binary.initial_regs,
+            initial_pc: binary.initial_pc,
+            initial_page_map: binary
+                .initial_page_map
+                .into_iter()
+                .map(|(address, length, is_writable)| Page {
+                    address,
+                    length,
+                    is_writable,
+                })
+                .collect(),
+            initial_memory: chunks(binary.initial_memory),
+            initial_gas: binary.initial_gas,
+            program: binary.program,
+            expected_status: binary.expected_status,
+            expected_page_fault_address: binary.expected_page_fault_address,
+            expected_regs: binary.expected_regs.to_vec(),
+            expected_pc: binary.expected_pc,
+            expected_memory: chunks(binary.expected_memory),
+            expected_gas: binary.expected_gas,
+        })
+    }
+}
+
+/// Encode a message of the binary framing (without the length prefix).
+pub fn encode_binary(json: &TestcaseJson) -> Vec<u8> {
+    let mut payload = vec![BINARY_VERSION];
+    BinaryTestcase::from(json).encode_to(&mut payload);
+    payload
+}
+
+/// Decode a message of the binary framing (without the length prefix).
+pub fn decode_binary(payload: &[u8]) -> Result<TestcaseJson, String> {
+    let Some((version, mut payload)) = payload.split_first() else {
+        return Err("Empty message.".into());
+    };
+    if *version != BINARY_VERSION {
+        return Err(format!(
+            "Unsupported binary framing version {version}, expected {BINARY_VERSION}."
+        ));
+    }
+    BinaryTestcase::decode_all(&mut payload)
+        .map_err(|e| format!("Invalid SCALE message: {e}"))?
+        .try_into()
+}
 
 #[derive(Debug)]
 pub struct JsonStdin<Read, Write> {
     json: TestcaseJson,
     output: OutputState,
+    framing: Framing,
+    /// Captured stderr of the process (if enabled).
+    stderr: Option<Arc<Mutex<Vec<u8>>>>,
     stdin: Write,
     stdout: Read,
 }
 
 impl<Read, Write> JsonStdin<Read, Write> {
     pub fn new(stdout: Read, stdin: Write) -> Self {
+        Self::with_framing(stdout, stdin, Framing::Json)
+    }
+
+    pub fn with_framing(stdout: Read, stdin: Write, framing: Framing) -> Self {
         Self {
             json: Default::default(),
             output: Default::default(),
+            framing,
+            stderr: None,
             stdin,
             stdout,
         }
     }
-}
 
-impl<Read: std::io::Read, Write: std::io::Write> PvmApi for JsonStdin<Read, Write> {
-    fn run(&mut self) -> super::Result<Status> {
-        log::debug!("[stdin] Executing: {:?}", self.json);
+    /// Attach stderr output captured during each test case to `diagnostics`.
+    ///
+    /// The buffer is filled asynchronously (see `runner::init_pvms`) and cleared when a new program
+    /// is set, so the capture is best-effort: output written late may be attributed to the next test case.
+    pub fn with_stderr(mut self, stderr: Arc<Mutex<Vec<u8>>>) -> Self {
+        self.stderr = Some(stderr);
+        self
+    }
+}
 
+impl<Read: std::io::Read, Write: std::io::Write> JsonStdin<Read, Write> {
+    fn exchange_json(&mut self) -> super::Result<TestcaseJson> {
         let read = BufReader::new(&mut self.stdout);
         let json = serde_json::to_vec(&self.json).unwrap();
 
@@ -45,23 +213,103 @@ impl<Read: std::io::Read, Write: std::io::Write> PvmApi for JsonStdin<Read, Writ
         }
         log::debug!("[stdin] Response: {buffer}");
 
-        // copy results
-        let output: TestcaseJson = serde_json::from_str(&buffer).map_err(|e| {
+        serde_json::from_str(&buffer).map_err(|e| {
             log::error!("[stdin] Invalid response received: {e:?}");
             super::Error::wrap(e)
-        })?;
+        })
+    }
+
+    fn exchange_binary(&mut self) -> super::Result<TestcaseJson> {
+        let payload = encode_binary(&self.json);
+        let len = u32::try_from(payload.len()).map_err(|_| super::Error::Other("Request too large.".into()))?;
+
+        self.stdin.write_all(&len.to_le_bytes()).map_err(super::Error::wrap)?;
+        self.stdin.write_all(&payload).map_err(super::Error::wrap)?;
+        self.stdin.flush().map_err(super::Error::wrap)?;
+
+        // read results
+        let mut len = [0u8; 4];
+        self.stdout.read_exact(&mut len).map_err(super::Error::wrap)?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_MESSAGE_LEN {
+            log::error!("[stdin] Response too large: {len} bytes.");
+            return Err(super::Error::Other(format!(
+                "Invalid binary response: {len} bytes exceed the limit of {MAX_MESSAGE_LEN}."
+            )));
+        }
+        let mut buffer = vec![0u8; len as usize];
+        self.stdout.read_exact(&mut buffer).map_err(super::Error::wrap)?;
+        log::debug!("[stdin] Response: {} bytes", buffer.len());
+
+        decode_binary(&buffer).map_err(|e| {
+            log::error!("[stdin] Invalid response received: {e}");
+            super::Error::Other(format!("Invalid binary response: {e}"))
+        })
+    }
+}
+
+impl<Read, Write> JsonStdin<Read, Write> {
+    fn is_accessible(&self, page: u32) -> bool {
+        let address = u64::from(page) * u64::from(PAGE_SIZE);
+        self.json
+            .initial_page_map
+            .iter()
+            .any(|p| (u64::from(p.address)..u64::from(p.address) + u64::from(p.length)).contains(&address))
+    }
+
+    fn read_initial_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        read_initial_memory(
+            |page| self.is_accessible(page),
+            self.json.initial_memory.iter().map(|c| (c.address, &c.contents[..])),
+            address,
+            out,
+        )
+    }
+}
+
+impl<Read: std::io::Read, Write: std::io::Write> PvmApi for JsonStdin<Read, Write> {
+    fn run(&mut self) -> super::Result<Status> {
+        log::debug!("[stdin] Executing: {:?}", self.json);
+
+        let output = match self.framing {
+            Framing::Json => self.exchange_json()?,
+            Framing::Binary => self.exchange_binary()?,
+        };
+
+        // copy results
         self.output.gas = output.expected_gas;
         self.output.pc = Some(output.expected_pc);
         for (out, reg) in self.output.registers.iter_mut().zip(&output.expected_regs) {
             *out = *reg;
         }
+        self.output.memory = Some(
+            output
+                .expected_memory
+                .into_iter()
+                .map(|chunk| (chunk.address, chunk.contents))
+                .collect(),
+        );
 
         let status = match &*output.expected_status {
             "panic" => Status::Trap,
             "out-of-gas" => Status::OutOfGas,
             "halt" => Status::Halt,
-            "page-fault" => Status::Fault,
+            "page-fault" => match output.expected_page_fault_address {
+                Some(address) => Status::Fault(address),
+                None => {
+                    log::error!("[stdin] Page fault reported without an address.");
+                    return Err(super::Error::Other(
+                        "Invalid response: `page-fault` status without `expected-page-fault-address`.".into(),
+                    ));
+                }
+            },
             "host" => Status::Host,
+            ERROR_STATUS => {
+                log::error!("[stdin] The PVM failed to execute {}.", output.name);
+                return Err(super::Error::Other(
+                    "The PVM failed to execute the test case (`error` status).".into(),
+                ));
+            }
             _ => {
                 log::error!("Invalid output status {}", output.expected_status);
                 Status::Trap
@@ -96,24 +344,159 @@ impl<Read: std::io::Read, Write: std::io::Write> PvmApi for JsonStdin<Read, Writ
         self.json.initial_pc = pc;
     }
 
+    fn set_isa(&mut self, isa: Isa) -> super::Result<()> {
+        self.json.isa = isa;
+        Ok(())
+    }
+
     fn set_program(&mut self, code: &[u8], container: super::ProgramContainer) -> super::Result<()> {
         if let ProgramContainer::Generic = container {
+            if let Some(stderr) = &self.stderr {
+                stderr.lock().unwrap().clear();
+            }
             self.json.program = code.to_vec();
+            self.json.initial_page_map.clear();
+            self.json.initial_memory.clear();
             Ok(())
         } else {
             Err(super::Error::Other("Unsupported container format.".into()))
         }
     }
 
-    fn set_page(&mut self, _page: u32, _access: super::MemoryAccess) {
-        todo!()
+    fn set_page(&mut self, page: u32, access: super::MemoryAccess) {
+        let address = page * PAGE_SIZE;
+        self.json.initial_page_map.retain(|p| p.address != address);
+        self.json.initial_page_map.push(Page {
+            address,
+            length: PAGE_SIZE,
+            is_writable: access == MemoryAccess::Writeable,
+        });
+    }
+
+    fn read_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        match &self.output.memory {
+            Some(memory) => read_chunks(memory.iter().map(|(a, d)| (*a, &d[..])), address, out),
+            None => self.read_initial_memory(address, out),
+        }
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> super::Result<()> {
+        self.json.initial_memory.push(MemoryChunk {
+            address,
+            contents: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn diagnostics(&self) -> Option<String> {
+        let stderr = self.stderr.as_ref()?.lock().unwrap();
+        (!stderr.is_empty()).then(|| format!("stderr:\n{}", String::from_utf8_lossy(&stderr)))
     }
 
-    fn read_memory(&self, _address: u32, _out: &mut [u8]) -> super::Result<()> {
-        todo!()
+    fn snapshot(&self) -> super::Result<Snapshot> {
+        let mut pages = vec![];
+        for page in &self.json.initial_page_map {
+            let access = if page.is_writable {
+                MemoryAccess::Writeable
+            } else {
+                MemoryAccess::Readable
+            };
+            let start = page.address / PAGE_SIZE;
+            let end = (u64::from(page.address) + u64::from(page.length)).div_ceil(u64::from(PAGE_SIZE));
+            for index in start..end as u32 {
+                let address = index * PAGE_SIZE;
+                let mut data = vec![0u8; PAGE_SIZE as usize];
+                // the response only contains memory that was changed by the PVM.
+                self.read_initial_memory(address, &mut data)?;
+                for (chunk_address, chunk) in self.output.memory.iter().flatten() {
+                    copy_overlap(address, &mut data, *chunk_address, chunk);
+                }
+                pages.push((index, access, data));
+            }
+        }
+
+        let (registers, gas, pc) = match self.output.pc {
+            Some(pc) => (self.output.registers, self.output.gas, pc),
+            None => (self.json.initial_regs, self.json.initial_gas, self.json.initial_pc),
+        };
+        Ok(Snapshot {
+            registers,
+            pc,
+            gas,
+            pages,
+        })
+    }
+
+    fn restore(&mut self, snapshot: &Snapshot) -> super::Result<()> {
+        self.json.initial_regs = snapshot.registers;
+        self.json.initial_gas = snapshot.gas;
+        self.json.initial_pc = snapshot.pc;
+        self.json.initial_page_map = snapshot
+            .pages
+            .iter()
+            .map(|(page, access, _)| Page {
+                address: page * PAGE_SIZE,
+                length: PAGE_SIZE,
+                is_writable: *access == MemoryAccess::Writeable,
+            })
+            .collect();
+        self.json.initial_memory = snapshot
+            .pages
+            .iter()
+            .map(|(page, _, data)| MemoryChunk {
+                address: page * PAGE_SIZE,
+                contents: data.clone(),
+            })
+            .collect();
+        self.output = Default::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::NUMBER_OF_REGISTERS;
+
+    #[test]
+    fn binary_messages_round_trip() {
+        let json = TestcaseJson {
+            name: "inst_add".into(),
+            isa: Isa::Bits32,
+            initial_regs: [7; NUMBER_OF_REGISTERS],
+            initial_page_map: vec![Page {
+                address: 0x20000,
+                length: PAGE_SIZE,
+                is_writable: true,
+            }],
+            initial_memory: vec![MemoryChunk {
+                address: 0x20000,
+                contents: vec![1, 2, 3],
+            }],
+            initial_gas: -1,
+            program: vec![0, 0, 1, 0, 1],
+            expected_status: "page-fault".into(),
+            expected_page_fault_address: Some(0x21000),
+            expected_regs: vec![1; NUMBER_OF_REGISTERS],
+            ..Default::default()
+        };
+        let payload = encode_binary(&json);
+        assert_eq!(payload[0], BINARY_VERSION);
+        let decoded = decode_binary(&payload).unwrap();
+        assert_eq!(
+            serde_json::to_value(decoded).unwrap(),
+            serde_json::to_value(json).unwrap()
+        );
     }
 
-    fn write_memory(&mut self, _address: u32, _data: &[u8]) -> super::Result<()> {
-        todo!()
+    #[test]
+    fn other_versions_and_trailing_bytes_are_rejected() {
+        let mut payload = encode_binary(&TestcaseJson::default());
+        payload.push(0);
+        assert!(decode_binary(&payload).is_err());
+        payload.pop();
+        payload[0] = BINARY_VERSION + 1;
+        assert!(decode_binary(&payload).is_err());
+        assert!(decode_binary(&[]).is_err());
     }
 }
@@ -0,0 +1,257 @@
+//! PVMs compiled to WebAssembly, running in-process in an embedded `wasmtime` runtime.
+//!
+//! The module must export its linear `memory` and the following functions (`u32` values are
+//! passed as `i32`, buffers are pointers into the module's memory):
+//!
+//! - `pvm_alloc(size: u32) -> u32` / `pvm_free(ptr: u32, size: u32)` - buffers used to pass data in and out,
+//! - `pvm_run() -> i32` / `pvm_step() -> i32` - returns the status code (see `Status`), negative on error,
+//! - `pvm_get_fault_address() -> u32` - page-aligned address of the last page fault,
+//! - `pvm_get_gas() -> i64` / `pvm_set_gas(gas: i64)`,
+//! - `pvm_get_registers(ptr: u32)` / `pvm_set_registers(ptr: u32)` - 13 little-endian `u64` values,
+//! - `pvm_get_pc() -> i64` (`-1` if unknown) / `pvm_set_next_pc(pc: u32)`,
+//! - `pvm_set_program(ptr: u32, len: u32, container: u32) -> i32` - same codes as in `ffi/pvm.h`, resets memory,
+//! - `pvm_set_page(page: u32, access: u32)` - page index and access as in `ffi/pvm.h`,
+//! - `pvm_read_memory(address: u32, ptr: u32, len: u32) -> i32` / `pvm_write_memory(address: u32, ptr: u32, len: u32) -> i32`
+//!   returning `0` on success.
+//!
+//! AssemblyScript's `env.abort` import is provided and traps.
+//!
+//! Like `ffi/pvm.h`, the interface can't select the instruction set, so only 64-bit programs are supported.
+
+use std::{cell::RefCell, path::Path};
+
+use wasmtime::{Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use super::{common, Error, MemoryAccess, ProgramContainer, PvmApi, Status, NUMBER_OF_REGISTERS};
+
+struct Exports {
+    alloc: TypedFunc<i32, i32>,
+    free: TypedFunc<(i32, i32), ()>,
+    run: TypedFunc<(), i32>,
+    step: TypedFunc<(), i32>,
+    get_fault_address: TypedFunc<(), i32>,
+    get_gas: TypedFunc<(), i64>,
+    set_gas: TypedFunc<i64, ()>,
+    get_registers: TypedFunc<i32, ()>,
+    set_registers: TypedFunc<i32, ()>,
+    get_pc: TypedFunc<(), i64>,
+    set_next_pc: TypedFunc<i32, ()>,
+    set_program: TypedFunc<(i32, i32, i32), i32>,
+    set_page: TypedFunc<(i32, i32), ()>,
+    read_memory: TypedFunc<(i32, i32, i32), i32>,
+    write_memory: TypedFunc<(i32, i32, i32), i32>,
+}
+
+pub struct WasmPvm {
+    // NOTE: `PvmApi` getters take `&self`, but calling into wasm requires `&mut Store`.
+    store: RefCell<Store<()>>,
+    memory: Memory,
+    exports: Exports,
+}
+
+fn wrap(e: wasmtime::Error) -> Error {
+    Error::Other(format!("wasm: {e:#}"))
+}
+
+impl WasmPvm {
+    /// Compile and instantiate the module.
+    pub fn load(path: &Path) -> super::Result<Self> {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path).map_err(wrap)?;
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "abort",
+                |_msg: i32, _file: i32, line: i32, column: i32| -> wasmtime::Result<()> {
+                    Err(wasmtime::Error::msg(format!("abort called at {line}:{column}")))
+                },
+            )
+            .map_err(wrap)?;
+
+        let mut store = Store::new(&engine, ());
+        let instance = linker.instantiate(&mut store, &module).map_err(wrap)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| Error::Other("wasm: module does not export `memory`".into()))?;
+
+        macro_rules! export {
+            ($name: literal) => {
+                instance.get_typed_func(&mut store, $name).map_err(wrap)?
+            };
+        }
+
+        let exports = Exports {
+            alloc: export!("pvm_alloc"),
+            free: export!("pvm_free"),
+            run: export!("pvm_run"),
+            step: export!("pvm_step"),
+            get_fault_address: export!("pvm_get_fault_address"),
+            get_gas: export!("pvm_get_gas"),
+            set_gas: export!("pvm_set_gas"),
+            get_registers: export!("pvm_get_registers"),
+            set_registers: export!("pvm_set_registers"),
+            get_pc: export!("pvm_get_pc"),
+            set_next_pc: export!("pvm_set_next_pc"),
+            set_program: export!("pvm_set_program"),
+            set_page: export!("pvm_set_page"),
+            read_memory: export!("pvm_read_memory"),
+            write_memory: export!("pvm_write_memory"),
+        };
+
+        Ok(Self {
+            store: RefCell::new(store),
+            memory,
+            exports,
+        })
+    }
+
+    /// Copy `data` into a freshly allocated buffer and call `f` with its pointer.
+    fn with_input<R>(
+        &self,
+        data: &[u8],
+        f: impl FnOnce(&mut Store<()>, i32) -> wasmtime::Result<R>,
+    ) -> super::Result<R> {
+        self.with_buffer(data.len(), |store, memory, ptr| {
+            memory.write(&mut *store, ptr as u32 as usize, data)?;
+            f(store, ptr)
+        })
+    }
+
+    /// Call `f` with a freshly allocated buffer of `len` bytes and copy its contents to `out` afterwards.
+    fn with_output<R>(
+        &self,
+        out: &mut [u8],
+        f: impl FnOnce(&mut Store<()>, i32) -> wasmtime::Result<R>,
+    ) -> super::Result<R> {
+        self.with_buffer(out.len(), |store, memory, ptr| {
+            let res = f(store, ptr)?;
+            memory.read(&*store, ptr as u32 as usize, out)?;
+            Ok(res)
+        })
+    }
+
+    fn with_buffer<R>(
+        &self,
+        len: usize,
+        f: impl FnOnce(&mut Store<()>, Memory, i32) -> wasmtime::Result<R>,
+    ) -> super::Result<R> {
+        let len = i32::try_from(len).map_err(|_| Error::Other("wasm: buffer too large".into()))?;
+        let mut store = self.store.borrow_mut();
+        let ptr = self.exports.alloc.call(&mut *store, len).map_err(wrap)?;
+        let res = f(&mut store, self.memory, ptr);
+        self.exports.free.call(&mut *store, (ptr, len)).map_err(wrap)?;
+        res.map_err(wrap)
+    }
+
+    fn call<P: wasmtime::WasmParams, R: wasmtime::WasmResults>(
+        &self,
+        func: &TypedFunc<P, R>,
+        params: P,
+    ) -> super::Result<R> {
+        func.call(&mut *self.store.borrow_mut(), params).map_err(wrap)
+    }
+
+    fn status(&self, code: i32) -> super::Result<Status> {
+        let fault_address = || match self.call(&self.exports.get_fault_address, ()) {
+            Ok(address) => address as u32,
+            Err(e) => {
+                log::error!("[wasm] Unable to read fault address: {e}");
+                0
+            }
+        };
+        common::status_from_code(code, fault_address)
+    }
+}
+
+impl PvmApi for WasmPvm {
+    fn run(&mut self) -> super::Result<Status> {
+        let code = self.call(&self.exports.run, ())?;
+        self.status(code)
+    }
+
+    fn step(&mut self) -> super::Result<Status> {
+        let code = self.call(&self.exports.step, ())?;
+        self.status(code)
+    }
+
+    fn gas(&self) -> i64 {
+        self.call(&self.exports.get_gas, ()).unwrap_or_else(|e| {
+            log::error!("[wasm] Unable to read gas: {e}");
+            0
+        })
+    }
+
+    fn set_gas(&mut self, gas: i64) {
+        if let Err(e) = self.call(&self.exports.set_gas, gas) {
+            log::error!("[wasm] Unable to set gas: {e}");
+        }
+    }
+
+    fn registers(&self) -> [u64; NUMBER_OF_REGISTERS] {
+        let mut bytes = [0u8; NUMBER_OF_REGISTERS * 8];
+        if let Err(e) = self.with_output(&mut bytes, |store, ptr| self.exports.get_registers.call(store, ptr)) {
+            log::error!("[wasm] Unable to read registers: {e}");
+        }
+        let mut registers = [0u64; NUMBER_OF_REGISTERS];
+        for (reg, bytes) in registers.iter_mut().zip(bytes.chunks_exact(8)) {
+            *reg = u64::from_le_bytes(bytes.try_into().expect("chunks are 8 bytes; qed"));
+        }
+        registers
+    }
+
+    fn set_registers(&mut self, registers: &[u64; NUMBER_OF_REGISTERS]) {
+        let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_le_bytes()).collect();
+        if let Err(e) = self.with_input(&bytes, |store, ptr| self.exports.set_registers.call(store, ptr)) {
+            log::error!("[wasm] Unable to set registers: {e}");
+        }
+    }
+
+    fn program_counter(&self) -> Option<u32> {
+        match self.call(&self.exports.get_pc, ()) {
+            Ok(pc) => u32::try_from(pc).ok(),
+            Err(e) => {
+                log::error!("[wasm] Unable to read pc: {e}");
+                None
+            }
+        }
+    }
+
+    fn set_next_program_counter(&mut self, pc: u32) {
+        if let Err(e) = self.call(&self.exports.set_next_pc, pc as i32) {
+            log::error!("[wasm] Unable to set pc: {e}");
+        }
+    }
+
+    fn set_program(&mut self, code: &[u8], container: ProgramContainer) -> super::Result<()> {
+        let container = common::container_code(container) as i32;
+        let len = code.len() as i32;
+        let res = self.with_input(code, |store, ptr| {
+            self.exports.set_program.call(store, (ptr, len, container))
+        })?;
+        common::program_result(res)
+    }
+
+    fn set_page(&mut self, page: u32, access: MemoryAccess) {
+        let access = common::access_code(access) as i32;
+        if let Err(e) = self.call(&self.exports.set_page, (page as i32, access)) {
+            log::error!("[wasm] Unable to set page: {e}");
+        }
+    }
+
+    fn read_memory(&self, address: u32, out: &mut [u8]) -> super::Result<()> {
+        let len = out.len() as i32;
+        let res = self.with_output(out, |store, ptr| {
+            self.exports.read_memory.call(store, (address as i32, ptr, len))
+        })?;
+        common::memory_result(res, "read_memory", address)
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> super::Result<()> {
+        let len = data.len() as i32;
+        let res = self.with_input(data, |store, ptr| {
+            self.exports.write_memory.call(store, (address as i32, ptr, len))
+        })?;
+        common::memory_result(res, "write_memory", address)
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{
+    api::{self, Isa, ProgramContainer, PvmApi},
+    json::{MemoryChunk, Page, TestcaseJson},
+    runner,
+};
+
+/// A minimal generic program consisting of a single `trap` instruction.
+///
+/// Used to measure the fixed per-run cost of each PVM (process round-trip,
+/// (de)serialization, instantiation), so that it can be reported separately.
+const BASELINE_PROGRAM: &[u8] = &[0, 0, 1, 0, 1];
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub iterations: usize,
+    pub warmup: usize,
+    /// Initial gas for programs loaded from raw polkavm blobs.
+    pub gas: i64,
+    /// Override instruction set width of JSON test cases.
+    pub isa: Option<Isa>,
+}
+
+/// A program to benchmark along with its initial state.
+#[derive(Debug)]
+pub struct BenchCase {
+    pub name: String,
+    pub program: Vec<u8>,
+    pub container: ProgramContainer,
+    pub isa: Isa,
+    pub registers: [u64; api::NUMBER_OF_REGISTERS],
+    pub pc: u32,
+    pub gas: i64,
+    pub pages: Vec<Page>,
+    pub memory: Vec<MemoryChunk>,
+}
+
+impl BenchCase {
+    /// Load either a `TestcaseJson` (`*.json`) or a raw polkavm blob (anything else).
+    pub fn load(path: &Path, gas: i64) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let is_json = path.extension().map(|ext| ext == "json").unwrap_or(false);
+        if is_json {
+            let json: TestcaseJson =
+                serde_json::from_slice(&data).with_context(|| "Failed to parse JSON file.".to_string())?;
+            Ok(Self {
+                name: json.name,
+                program: json.program,
+                container: ProgramContainer::Generic,
+                isa: json.isa,
+                registers: json.initial_regs,
+                pc: json.initial_pc,
+                gas: json.initial_gas,
+                pages: json.initial_page_map,
+                memory: json.initial_memory,
+            })
+        } else {
+            Ok(Self {
+                name: path.display().to_string(),
+                program: data,
+                container: ProgramContainer::PolkaVM,
+                isa: Isa::Bits64,
+                registers: Default::default(),
+                pc: 0,
+                gas,
+                pages: vec![],
+                memory: vec![],
+            })
+        }
+    }
+
+    fn baseline() -> Self {
+        Self {
+            name: "baseline".into(),
+            program: BASELINE_PROGRAM.to_vec(),
+            container: ProgramContainer::Generic,
+            isa: Isa::Bits64,
+            registers: Default::default(),
+            pc: 0,
+            gas: 1,
+            pages: vec![],
+            memory: vec![],
+        }
+    }
+}
+
+/// Summary statistics over a set of timing samples.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub runs: usize,
+    pub mean: Duration,
+    pub median: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub stddev: Duration,
+}
+
+impl Stats {
+    pub fn new(mut samples: Vec<Duration>) -> Self {
+        assert!(!samples.is_empty());
+        samples.sort();
+
+        let runs = samples.len();
+        let secs: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / runs as f64;
+        let variance = secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / runs as f64;
+
+        Self {
+            runs,
+            mean: Duration::from_secs_f64(mean),
+            median: samples[runs / 2],
+            min: samples[0],
+            max: samples[runs - 1],
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+}
+
+/// Timing of a single case on a single PVM.
+#[derive(Debug)]
+pub struct Measurement {
+    pub status: api::Status,
+    pub gas_used: i64,
+    pub stats: Stats,
+}
+
+impl Measurement {
+    /// Gas consumed per second of pure execution time (i.e. excluding `overhead`).
+    pub fn gas_per_second(&self, overhead: Duration) -> f64 {
+        let exec = self.stats.mean.saturating_sub(overhead);
+        let exec = if exec.is_zero() { self.stats.mean } else { exec };
+        self.gas_used as f64 / exec.as_secs_f64()
+    }
+}
+
+/// Run `case` `options.iterations` times (after `options.warmup` discarded runs).
+pub fn measure(pvm: &mut dyn PvmApi, case: &BenchCase, options: &Options) -> api::Result<Measurement> {
+    let mut samples = Vec::with_capacity(options.iterations);
+    let mut last = None;
+    pvm.set_isa(case.isa)?;
+    for i in 0..options.warmup + options.iterations.max(1) {
+        pvm.set_program(&case.program, case.container)?;
+        pvm.set_gas(case.gas);
+        pvm.set_registers(&case.registers);
+        pvm.set_next_program_counter(case.pc);
+        runner::setup_memory(pvm, &case.pages, &case.memory)?;
+
+        let start = Instant::now();
+        let status = pvm.run()?;
+        let elapsed = start.elapsed();
+
+        if i >= options.warmup {
+            samples.push(elapsed);
+        }
+        last = Some(status);
+    }
+
+    Ok(Measurement {
+        status: last.expect("at least one iteration is always executed; qed"),
+        gas_used: case.gas - pvm.gas(),
+        stats: Stats::new(samples),
+    })
+}
+
+pub fn run(pvms: Vec<(String, Box<dyn PvmApi>)>, files: &[PathBuf], options: &Options) -> anyhow::Result<()> {
+    let cases = files
+        .iter()
+        .map(|file| {
+            let mut case = BenchCase::load(file, options.gas)?;
+            if let (Some(isa), ProgramContainer::Generic) = (options.isa, case.container) {
+                case.isa = isa;
+            }
+            Ok(case)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let baseline = BenchCase::baseline();
+
+    for (name, mut pvm) in pvms {
+        let overhead = measure(pvm.as_mut(), &baseline, options)
+            .with_context(|| format!("[{name}] Failed to run the baseline program."))?
+            .stats;
+        println!(
+            "[{name}] overhead per run: {:?} ± {:?} (min {:?}, max {:?})",
+            overhead.mean, overhead.stddev, overhead.min, overhead.max
+        );
+
+        for case in &cases {
+            let m = match measure(pvm.as_mut(), case, options) {
+                Ok(m) => m,
+                Err(e) => {
+                    log::warn!("[{name}] Skipping {}: {e}", case.name);
+                    continue;
+                }
+            };
+            let s = &m.stats;
+            println!(
+                "[{name}] {}: {} runs, {:?} ± {:?} (min {:?}, median {:?}, max {:?}), exec {:?}, {:.2} Mgas/s, status {}",
+                case.name,
+                s.runs,
+                s.mean,
+                s.stddev,
+                s.min,
+                s.median,
+                s.max,
+                s.mean.saturating_sub(overhead.mean),
+                m.gas_per_second(overhead.mean) / 1_000_000.0,
+                m.status,
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+//! Serving the stdin protocol spoken by `api::stdin::JsonStdin`, so that any `PvmApi`
+//! implementation (e.g. the built-in polkavm) can be used as a stdin PVM by other harnesses.
+//!
+//! Each request is a `TestcaseJson` with the initial state. The response is the same test case
+//! with the `expected-*` fields filled with the results of the execution. `expected-memory`
+//! contains all mapped memory.
+//!
+//! If the test case can't be executed (e.g. the program is invalid or the ISA is not supported),
+//! the response has the `stdin::ERROR_STATUS` status, which no execution produces, so it can't be
+//! mistaken for a `panic`. The other `expected-*` fields repeat the initial state and the bridge
+//! keeps serving.
+//!
+//! With several PVMs (a collection) the disagreements between them are logged for every request.
+
+use std::io::{BufRead, Write};
+
+use crate::{
+    api::{
+        stdin::{self, Framing},
+        PvmApi,
+    },
+    json::{MemoryChunk, TestcaseJson},
+    runner,
+};
+
+/// Handle requests from `input` until it's closed, writing the responses to `output`.
+pub fn serve(
+    pvm: &mut dyn PvmApi,
+    framing: Framing,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> anyhow::Result<()> {
+    loop {
+        let request = match framing {
+            Framing::Json => read_json(&mut input)?,
+            Framing::Binary => read_binary(&mut input)?,
+        };
+        let Some(request) = request else {
+            return Ok(());
+        };
+        log::debug!("[bridge] Request: {}", request.name);
+
+        let response = match execute(pvm, request.clone()) {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("[bridge] Failed to execute {}: {e:?}", request.name);
+                failed(request)
+            }
+        };
+        // NOTE: a collection records every disagreement, so they have to be drained after each request.
+        for mismatch in pvm.take_mismatches() {
+            let wrong = mismatch.values.iter().filter(|(name, _)| mismatch.wrong.contains(name));
+            for (name, value) in wrong {
+                log::error!("[bridge] {}: [{}] {name}: {value}", response.name, mismatch.field);
+            }
+        }
+        match framing {
+            Framing::Json => {
+                serde_json::to_writer(&mut output, &response)?;
+                output.write_all(b"\n\n")?;
+            }
+            Framing::Binary => {
+                let payload = stdin::encode_binary(&response);
+                output.write_all(&u32::try_from(payload.len())?.to_le_bytes())?;
+                output.write_all(&payload)?;
+            }
+        }
+        output.flush()?;
+    }
+}
+
+/// Run the initial state of the test case and fill in the results.
+pub fn execute(pvm: &mut dyn PvmApi, mut json: TestcaseJson) -> anyhow::Result<TestcaseJson> {
+    runner::setup_testcase(pvm, &json)?;
+    let status = pvm.run()?;
+
+    json.expected_status = status.to_string();
+    json.expected_page_fault_address = status.fault_address();
+    json.expected_regs = pvm.registers().to_vec();
+    json.expected_pc = pvm.program_counter().unwrap_or_default();
+    json.expected_gas = pvm.gas();
+    json.expected_memory = json
+        .initial_page_map
+        .iter()
+        .filter_map(|page| {
+            let mut contents = vec![0u8; page.length as usize];
+            match pvm.read_memory(page.address, &mut contents) {
+                Ok(()) => Some(MemoryChunk {
+                    address: page.address,
+                    contents,
+                }),
+                Err(e) => {
+                    log::warn!("[bridge] Unable to read memory at {}: {e}", page.address);
+                    None
+                }
+            }
+        })
+        .collect();
+    Ok(json)
+}
+
+/// The response to a test case which couldn't be executed: `error` status with the initial state.
+fn failed(mut json: TestcaseJson) -> TestcaseJson {
+    json.expected_status = stdin::ERROR_STATUS.to_string();
+    json.expected_page_fault_address = None;
+    json.expected_regs = json.initial_regs.to_vec();
+    json.expected_pc = json.initial_pc;
+    json.expected_gas = json.initial_gas;
+    json.expected_memory = json.initial_memory.clone();
+    json
+}
+
+/// Read a JSON message terminated with an empty line. Returns `None` at the end of input.
+fn read_json(input: &mut impl BufRead) -> anyhow::Result<Option<TestcaseJson>> {
+    let mut buffer = String::new();
+    for line in input.lines() {
+        let line = line?;
+        if line.is_empty() {
+            // skip empty lines before the message.
+            if buffer.is_empty() {
+                continue;
+            }
+            break;
+        }
+        buffer.push_str(&line);
+    }
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&buffer)?))
+}
+
+/// Read a length-prefixed binary message. Returns `None` at the end of input.
+fn read_binary(input: &mut impl BufRead) -> anyhow::Result<Option<TestcaseJson>> {
+    if input.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    anyhow::ensure!(
+        len <= stdin::MAX_MESSAGE_LEN,
+        "Invalid binary request: {len} bytes exceed the limit of {}.",
+        stdin::MAX_MESSAGE_LEN
+    );
+    let mut buffer = vec![0u8; len as usize];
+    input.read_exact(&mut buffer)?;
+    stdin::decode_binary(&buffer).map(Some).map_err(anyhow::Error::msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{reference::Reference, stdin::JsonStdin};
+
+    #[test]
+    fn failures_are_not_reported_as_panics() {
+        let request = TestcaseJson {
+            name: "invalid".into(),
+            program: vec![0xff],
+            ..Default::default()
+        };
+        let mut input = serde_json::to_vec(&request).unwrap();
+        input.extend_from_slice(b"\n\n");
+        let mut output = vec![];
+        serve(&mut Reference::default(), Framing::Json, &input[..], &mut output).unwrap();
+
+        let response: TestcaseJson = serde_json::from_slice(&output).unwrap();
+        assert_eq!(response.expected_status, stdin::ERROR_STATUS);
+        let mut pvm = JsonStdin::new(&output[..], vec![]);
+        assert!(pvm.run().is_err());
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let input = (stdin::MAX_MESSAGE_LEN + 1).to_le_bytes();
+        let result = serve(&mut Reference::default(), Framing::Binary, &input[..], vec![]);
+        assert!(result.unwrap_err().to_string().contains("limit"));
+
+        let mut pvm = JsonStdin::with_framing(&input[..], vec![], Framing::Binary);
+        assert!(pvm.run().unwrap_err().to_string().contains("limit"));
+    }
+}
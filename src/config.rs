@@ -1,46 +1,400 @@
+use anyhow::Context;
 use serde::Deserialize;
 use std::{
+    collections::BTreeMap,
     fs,
     path::{Path, PathBuf},
 };
 
+use crate::api::{collection::Policy, stdin::Framing};
+
+/// Read the config file along with all included files and validate it.
+///
+/// Every file is validated on its own, and PVMs or profiles defined in more than one file are rejected
+/// when merging them.
 pub fn read_config_file(path: &Path) -> anyhow::Result<Config> {
-    let config = fs::read_to_string(path)?;
-    let config = toml::from_str(&config)?;
-    Ok(config)
+    load(path, &mut vec![])
+}
+
+fn load(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<Config> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    anyhow::ensure!(!stack.contains(&canonical), "Circular include of {}", path.display());
+
+    let source = fs::read_to_string(path)?;
+    let config: Config = toml::from_str(&source).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+    validate(path, &source, &config)?;
+
+    stack.push(canonical);
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut merged = Config::default();
+    for include in &config.include {
+        let include = base.join(include);
+        let config = load(&include, stack)?;
+        merged.merge(config, &include)?;
+    }
+    stack.pop();
+
+    merged.merge(config, path)?;
+    Ok(merged)
 }
 
-#[derive(Deserialize)]
+/// Check for duplicate PVM names and missing files, reporting them with line numbers.
+fn validate(path: &Path, source: &str, config: &Config) -> anyhow::Result<()> {
+    let mut errors = vec![];
+    // find the line of the `nth` entry where `key` is set to `value`.
+    let mut report = |key: &str, value: &str, nth: usize, message: String| {
+        let quoted = format!("\"{value}\"");
+        let line = source
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.trim_start().starts_with(key) && line.contains(&quoted))
+            .nth(nth)
+            .map(|(i, _)| format!(":{}", i + 1))
+            .unwrap_or_default();
+        errors.push(format!("{}{line}: {message}", path.display()));
+    };
+
+    let top = std::iter::once((None, &config.pvm, &config.tests));
+    let profiles = config.profiles.iter().map(|(name, p)| (Some(name), &p.pvm, &p.tests));
+    for (profile, pvms, tests) in top.chain(profiles) {
+        let location = profile.map(|p| format!(" in profile {p:?}")).unwrap_or_default();
+        let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+        for pvm in pvms {
+            let (key, value) = pvm.config_key();
+            let count = seen.entry(pvm.name()).or_default();
+            if *count > 0 {
+                report(
+                    key,
+                    &value,
+                    *count,
+                    format!("Duplicate PVM name{location}: {:?}", pvm.name()),
+                );
+            }
+            *count += 1;
+            if let Some(missing) = pvm.missing_file() {
+                report(key, &value, 0, format!("File not found: {}", missing.display()));
+            }
+        }
+        for dir in tests {
+            if !dir.is_dir() {
+                let value = dir.display().to_string();
+                report("tests", &value, 0, format!("Test directory not found: {value}"));
+            }
+        }
+    }
+
+    anyhow::ensure!(errors.is_empty(), "Invalid config:\n{}", errors.join("\n"));
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Other config files to load first (relative to this file).
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+    #[serde(default)]
     pub pvm: Vec<Pvm>,
+    /// Directories with JSON test cases.
+    #[serde(default)]
+    pub tests: Vec<PathBuf>,
+    /// Only run test files with names matching any of these patterns (`*` is a wildcard).
+    #[serde(default)]
+    pub filter: Vec<String>,
+    /// Which PVM to trust when their results differ (`first` by default).
+    pub policy: Option<Policy>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named set of PVMs and tests, selected with `--profile`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub pvm: Vec<Pvm>,
+    #[serde(default)]
+    pub tests: Vec<PathBuf>,
+    #[serde(default)]
+    pub filter: Vec<String>,
+    pub policy: Option<Policy>,
+}
+
+impl Config {
+    /// Add the settings of `other` (loaded from `path`), rejecting PVMs and profiles defined again.
+    fn merge(&mut self, other: Config, path: &Path) -> anyhow::Result<()> {
+        let mut errors = vec![];
+        for pvm in other
+            .pvm
+            .iter()
+            .filter(|p| self.pvm.iter().any(|q| q.name() == p.name()))
+        {
+            errors.push(format!(
+                "{}: Duplicate PVM name: {:?} (already defined in an included file)",
+                path.display(),
+                pvm.name()
+            ));
+        }
+        for name in other.profiles.keys().filter(|name| self.profiles.contains_key(*name)) {
+            errors.push(format!(
+                "{}: Duplicate profile: {name:?} (already defined in an included file)",
+                path.display()
+            ));
+        }
+        anyhow::ensure!(errors.is_empty(), "Invalid config:\n{}", errors.join("\n"));
+
+        self.pvm.extend(other.pvm);
+        self.tests.extend(other.tests);
+        self.filter.extend(other.filter);
+        self.policy = other.policy.or(self.policy.take());
+        self.profiles.extend(other.profiles);
+        Ok(())
+    }
+
+    /// Resolve the settings to use: the selected profile with top-level values as fallback.
+    pub fn profile(mut self, name: Option<&str>) -> anyhow::Result<Profile> {
+        let top = Profile {
+            pvm: self.pvm,
+            tests: self.tests,
+            filter: self.filter,
+            policy: self.policy,
+        };
+        let Some(name) = name else {
+            return Ok(top);
+        };
+        let profile = self
+            .profiles
+            .remove(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown profile: {name:?}"))?;
+        Ok(Profile {
+            pvm: if profile.pvm.is_empty() { top.pvm } else { profile.pvm },
+            tests: if profile.tests.is_empty() {
+                top.tests
+            } else {
+                profile.tests
+            },
+            filter: if profile.filter.is_empty() {
+                top.filter
+            } else {
+                profile.filter
+            },
+            policy: profile.policy.or(top.policy),
+        })
+    }
+}
+
+impl Profile {
+    /// Add PVMs given on the command line, replacing configured ones with the same name.
+    ///
+    /// They come before the configured ones, so the `first` policy trusts the first one given on the command line.
+    pub fn add_pvms(&mut self, pvms: Vec<Pvm>) -> anyhow::Result<()> {
+        let names: Vec<_> = pvms.iter().map(Pvm::name).collect();
+        let configured = std::mem::replace(&mut self.pvm, pvms);
+        self.pvm
+            .extend(configured.into_iter().filter(|p| !names.contains(&p.name())));
+
+        let mut names: Vec<_> = self.pvm.iter().map(Pvm::name).collect();
+        names.sort();
+        if let Some(dup) = names.windows(2).find(|w| w[0] == w[1]) {
+            anyhow::bail!("Duplicate PVM name: {:?}", dup[0]);
+        }
+        Ok(())
+    }
+
+    /// Given files followed by JSON files from `tests` directories (recursively) matching the filters.
+    pub fn test_files(&self, mut files: Vec<PathBuf>) -> anyhow::Result<Vec<PathBuf>> {
+        let mut found = vec![];
+        for dir in &self.tests {
+            collect_json_files(dir, &mut found)?;
+        }
+        if !self.filter.is_empty() {
+            found.retain(|file| {
+                let name = file.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+                self.filter.iter().any(|pattern| matches_pattern(pattern, &name))
+            });
+        }
+        files.extend(found);
+        Ok(files)
+    }
+}
+
+pub(crate) fn collect_json_files(dir: &Path, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("Unable to read {}", dir.display()))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_json_files(&path, out)?;
+        } else if path.extension().map(|e| e == "json").unwrap_or(false) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Match `text` against a pattern where `*` matches any sequence of characters.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|i| text.is_char_boundary(*i))
+                .any(|i| matches_pattern(rest, &text[i..]))
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(tag = "kind")]
 #[serde(rename_all(deserialize = "lowercase"))]
+#[serde(deny_unknown_fields)]
 pub enum Pvm {
     /// Built-in polkavm native interface.
     PolkaVM,
 
+    /// Built-in reference interpreter (see `api::reference`).
+    Reference,
+
     /// stdin-based interface
-    Stdin { name: Option<String>, binary: PathBuf },
+    Stdin {
+        name: Option<String>,
+        binary: PathBuf,
+        /// Command line arguments passed to the binary.
+        #[serde(default)]
+        args: Vec<String>,
+        /// Additional environment variables.
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        /// Working directory of the process.
+        cwd: Option<PathBuf>,
+        #[serde(default)]
+        stderr: Stderr,
+        #[serde(default)]
+        framing: Framing,
+    },
+
+    /// shared library exporting the C interface from `ffi/pvm.h`
+    Ffi { name: Option<String>, library: PathBuf },
+
+    /// WebAssembly module exporting the interface described in `api::wasm`
+    Wasm { name: Option<String>, module: PathBuf },
 
     #[allow(dead_code)]
     /// jsonrpc-based interface
     JsonRpc { name: Option<String>, endpoint: String },
 }
 
+/// What to do with the standard error of stdin PVMs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stderr {
+    /// Print it along with the harness output.
+    #[default]
+    Inherit,
+    /// Write it to given file.
+    File(PathBuf),
+    /// Capture it and include the output of the failing test case in error reports (best-effort,
+    /// since it's read asynchronously).
+    Attach,
+}
+
+impl Pvm {
+    /// A stdin PVM with default process settings.
+    pub fn stdin(binary: PathBuf) -> Self {
+        Pvm::Stdin {
+            name: None,
+            binary,
+            args: vec![],
+            env: Default::default(),
+            cwd: None,
+            stderr: Default::default(),
+            framing: Default::default(),
+        }
+    }
+
+    /// Human-readable name of the PVM used in logs and reports.
+    pub fn name(&self) -> String {
+        match self {
+            Pvm::PolkaVM => "polkavm".into(),
+            Pvm::Reference => "reference".into(),
+            Pvm::Stdin { name, binary, .. } => name.clone().unwrap_or_else(|| binary.display().to_string()),
+            Pvm::Ffi { name, library } => name.clone().unwrap_or_else(|| library.display().to_string()),
+            Pvm::Wasm { name, module } => name.clone().unwrap_or_else(|| module.display().to_string()),
+            Pvm::JsonRpc { name, endpoint } => name.clone().unwrap_or_else(|| endpoint.clone()),
+        }
+    }
+}
+
+impl Pvm {
+    /// The key and value identifying this PVM in the config file.
+    fn config_key(&self) -> (&'static str, String) {
+        match self {
+            Pvm::Stdin { name: Some(name), .. }
+            | Pvm::Ffi { name: Some(name), .. }
+            | Pvm::Wasm { name: Some(name), .. }
+            | Pvm::JsonRpc { name: Some(name), .. } => ("name", name.clone()),
+            Pvm::PolkaVM => ("kind", "polkavm".into()),
+            Pvm::Reference => ("kind", "reference".into()),
+            Pvm::Stdin { binary, .. } => ("binary", binary.display().to_string()),
+            Pvm::Ffi { library, .. } => ("library", library.display().to_string()),
+            Pvm::Wasm { module, .. } => ("module", module.display().to_string()),
+            Pvm::JsonRpc { endpoint, .. } => ("endpoint", endpoint.clone()),
+        }
+    }
+
+    /// Binary, library or module of this PVM which does not exist.
+    fn missing_file(&self) -> Option<PathBuf> {
+        let path = match self {
+            Pvm::Stdin { binary, cwd, .. } => {
+                // binaries without a path are looked up in `PATH`.
+                if binary.components().count() == 1 {
+                    let found = std::env::var_os("PATH")
+                        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(binary).exists()))
+                        .unwrap_or(false);
+                    return (!found).then(|| binary.clone());
+                }
+                match cwd {
+                    Some(cwd) => cwd.join(binary),
+                    None => binary.clone(),
+                }
+            }
+            Pvm::Ffi { library, .. } => library.clone(),
+            Pvm::Wasm { module, .. } => module.clone(),
+            Pvm::PolkaVM | Pvm::Reference | Pvm::JsonRpc { .. } => return None,
+        };
+        (!path.exists()).then_some(path)
+    }
+}
+
 impl std::str::FromStr for Pvm {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "polkavm" {
             Ok(Pvm::PolkaVM)
+        } else if s == "reference" {
+            Ok(Pvm::Reference)
         } else if s.starts_with("stdin=") {
             let path = std::path::PathBuf::from_str(s.trim_start_matches("stdin="))?;
-            Ok(Pvm::Stdin {
+            Ok(Pvm::stdin(path))
+        } else if s.starts_with("ffi=") {
+            let path = std::path::PathBuf::from_str(s.trim_start_matches("ffi="))?;
+            Ok(Pvm::Ffi {
+                name: None,
+                library: path,
+            })
+        } else if s.starts_with("wasm=") {
+            let path = std::path::PathBuf::from_str(s.trim_start_matches("wasm="))?;
+            Ok(Pvm::Wasm {
                 name: None,
-                binary: path,
+                module: path,
             })
         } else if s.starts_with("jsonrpc=") {
             Ok(Pvm::JsonRpc {
@@ -52,3 +406,68 @@ impl std::str::FromStr for Pvm {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the files to a fresh temporary directory and read the first one.
+    fn read(files: &[(&str, &str)]) -> anyhow::Result<Config> {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("pvm-config-test-{}-{n}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents)?;
+        }
+        let config = read_config_file(&dir.join(files[0].0));
+        fs::remove_dir_all(&dir)?;
+        config
+    }
+
+    fn names(pvms: &[Pvm]) -> Vec<String> {
+        pvms.iter().map(Pvm::name).collect()
+    }
+
+    #[test]
+    fn includes_are_merged() {
+        let config = read(&[
+            ("config.toml", "include = [\"a.toml\"]\n[[pvm]]\nkind = \"reference\"\n"),
+            (
+                "a.toml",
+                "[[pvm]]\nkind = \"polkavm\"\n[profiles.ci]\npolicy = \"majority\"\n",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(names(&config.pvm), ["polkavm", "reference"]);
+        assert!(config.profiles.contains_key("ci"));
+    }
+
+    #[test]
+    fn duplicates_across_includes_are_rejected() {
+        let err = read(&[
+            ("config.toml", "include = [\"a.toml\"]\n[[pvm]]\nkind = \"polkavm\"\n"),
+            ("a.toml", "[[pvm]]\nkind = \"polkavm\"\n"),
+        ])
+        .unwrap_err();
+        assert!(format!("{err}").contains("Duplicate PVM name: \"polkavm\""), "{err}");
+
+        let err = read(&[
+            ("config.toml", "include = [\"a.toml\", \"b.toml\"]\n"),
+            ("a.toml", "[profiles.ci]\npolicy = \"majority\"\n"),
+            ("b.toml", "[profiles.ci]\npolicy = \"first\"\n"),
+        ])
+        .unwrap_err();
+        assert!(format!("{err}").contains("Duplicate profile: \"ci\""), "{err}");
+    }
+
+    #[test]
+    fn command_line_pvms_come_first() {
+        let mut profile = Profile {
+            pvm: vec![Pvm::PolkaVM, Pvm::Reference],
+            ..Default::default()
+        };
+        profile.add_pvms(vec![Pvm::Reference]).unwrap();
+        assert_eq!(names(&profile.pvm), ["reference", "polkavm"]);
+    }
+}
@@ -0,0 +1,458 @@
+//! Interactive debugger stepping through a test case on one or more PVMs side by side.
+
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    api::{self, collection::Mismatch, PvmApi, Snapshot, Status},
+    json::TestcaseJson,
+    runner,
+};
+
+const HELP: &str = "Commands:
+  s, step [n]                 execute n (default 1) instructions
+  c, continue                 run until a breakpoint or the end of execution
+  b, break <pc>               set a breakpoint
+  d, delete <pc>              remove a breakpoint
+  r, regs                     show the state of all PVMs
+  m, mem <address> <length>   dump memory
+  setreg <index> <value>      change a register
+  setmem <address> <hex>      write bytes to memory
+  gas <value>                 change the remaining gas
+  snapshot                    save the current state of all PVMs
+  rewind [i]                  restore the i-th (default: last) saved state
+  reset                       restart from the initial state
+  q, quit                     exit
+Numbers can be given in decimal or hex (0x...).";
+
+const COLUMN: usize = 22;
+
+/// Maximum number of bytes read by a single memory dump.
+pub const MAX_MEMORY_LENGTH: u32 = 4 * api::PAGE_SIZE;
+
+struct Session {
+    name: String,
+    pvm: Box<dyn PvmApi>,
+    /// Status of the last execution, `None` if nothing was executed yet.
+    status: Option<Status>,
+}
+
+impl Session {
+    fn is_running(&self) -> bool {
+        matches!(self.status, None | Some(Status::Ok))
+    }
+}
+
+/// State of a single PVM, see `Debugger::state`.
+#[derive(Debug, Clone)]
+pub struct State {
+    pub name: String,
+    /// Status of the last execution, `None` if nothing was executed yet.
+    pub status: Option<Status>,
+    pub pc: Option<u32>,
+    pub gas: i64,
+    pub registers: [u64; api::NUMBER_OF_REGISTERS],
+}
+
+pub struct Debugger {
+    sessions: Vec<Session>,
+    json: TestcaseJson,
+    breakpoints: BTreeSet<u32>,
+    /// Saved states, one per session.
+    snapshots: Vec<Vec<Snapshot>>,
+}
+
+impl Debugger {
+    pub fn new(pvms: Vec<(String, Box<dyn PvmApi>)>, json: TestcaseJson) -> api::Result<Self> {
+        let mut debugger = Self::without_testcase(pvms);
+        debugger.load(json)?;
+        Ok(debugger)
+    }
+
+    /// Create a debugger with an empty test case, which has to be `load`ed before execution.
+    pub fn without_testcase(pvms: Vec<(String, Box<dyn PvmApi>)>) -> Self {
+        let sessions = pvms
+            .into_iter()
+            .map(|(name, pvm)| Session {
+                name,
+                pvm,
+                status: None,
+            })
+            .collect();
+        Self {
+            sessions,
+            json: Default::default(),
+            breakpoints: Default::default(),
+            snapshots: Default::default(),
+        }
+    }
+
+    /// Replace the test case and load it into all PVMs. Breakpoints are kept.
+    pub fn load(&mut self, json: TestcaseJson) -> api::Result<()> {
+        self.json = json;
+        self.snapshots.clear();
+        self.reset()
+    }
+
+    /// Load the initial state of the test case into all PVMs.
+    pub fn reset(&mut self) -> api::Result<()> {
+        for session in &mut self.sessions {
+            runner::setup_testcase(session.pvm.as_mut(), &self.json)?;
+            session.status = None;
+        }
+        Ok(())
+    }
+
+    /// Execute up to `n` instructions on all PVMs which are still running.
+    pub fn step(&mut self, n: usize) -> api::Result<()> {
+        for session in self.sessions.iter_mut() {
+            for _ in 0..n {
+                if !session.is_running() {
+                    break;
+                }
+                session.status = Some(session.pvm.step()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Step all PVMs until they stop or hit a breakpoint.
+    pub fn resume(&mut self) -> api::Result<()> {
+        for session in self.sessions.iter_mut() {
+            while session.is_running() {
+                session.status = Some(session.pvm.step()?);
+                let pc = session.pvm.program_counter();
+                if pc.map(|pc| self.breakpoints.contains(&pc)).unwrap_or(false) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u32> {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut BTreeSet<u32> {
+        &mut self.breakpoints
+    }
+
+    /// Current state of all PVMs.
+    pub fn state(&self) -> Vec<State> {
+        self.sessions
+            .iter()
+            .map(|s| State {
+                name: s.name.clone(),
+                status: s.status,
+                pc: s.pvm.program_counter(),
+                gas: s.pvm.gas(),
+                registers: s.pvm.registers(),
+            })
+            .collect()
+    }
+
+    /// Disagreements recorded by each PVM since the last call, in the same order as `state`.
+    pub fn take_mismatches(&self) -> Vec<Vec<Mismatch>> {
+        self.sessions.iter().map(|s| s.pvm.take_mismatches()).collect()
+    }
+
+    /// Read memory of all PVMs, along with their names. `length` should not exceed `MAX_MEMORY_LENGTH`.
+    pub fn read_memory(&self, address: u32, length: u32) -> Vec<(&str, api::Result<Vec<u8>>)> {
+        self.sessions
+            .iter()
+            .map(|s| {
+                let mut data = vec![0u8; length as usize];
+                let res = s.pvm.read_memory(address, &mut data).map(|_| data);
+                (s.name.as_str(), res)
+            })
+            .collect()
+    }
+
+    pub fn set_register(&mut self, index: usize, value: u64) {
+        for session in &mut self.sessions {
+            let mut registers = session.pvm.registers();
+            registers[index] = value;
+            session.pvm.set_registers(&registers);
+        }
+    }
+
+    pub fn write_memory(&mut self, address: u32, data: &[u8]) -> api::Result<()> {
+        for session in &mut self.sessions {
+            session.pvm.write_memory(address, data)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_gas(&mut self, gas: i64) {
+        for session in &mut self.sessions {
+            session.pvm.set_gas(gas);
+        }
+    }
+
+    /// Print the state of all PVMs in columns.
+    pub fn print_state(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        row(out, "", self.sessions.iter().map(|s| s.name.clone()).collect())?;
+        row(
+            out,
+            "status",
+            self.sessions
+                .iter()
+                .map(|s| match s.status {
+                    Some(Status::Fault(address)) => format!("page-fault@{address:#x}"),
+                    Some(status) => status.to_string(),
+                    None => "-".into(),
+                })
+                .collect(),
+        )?;
+        row(
+            out,
+            "pc",
+            self.sessions
+                .iter()
+                .map(|s| {
+                    let pc = s.pvm.program_counter();
+                    let bp = if pc.map(|pc| self.breakpoints.contains(&pc)).unwrap_or(false) {
+                        "*"
+                    } else {
+                        ""
+                    };
+                    format!("{pc:?}{bp}")
+                })
+                .collect(),
+        )?;
+        row(
+            out,
+            "gas",
+            self.sessions.iter().map(|s| s.pvm.gas().to_string()).collect(),
+        )?;
+        let registers: Vec<_> = self.sessions.iter().map(|s| s.pvm.registers()).collect();
+        for i in 0..api::NUMBER_OF_REGISTERS {
+            row(
+                out,
+                &format!("r{i}"),
+                registers.iter().map(|r| format!("{:#x}", r[i])).collect(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn print_memory(&self, out: &mut dyn Write, address: u32, length: u32) -> std::io::Result<()> {
+        for (name, data) in self.read_memory(address, length) {
+            writeln!(out, "{name}:")?;
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    writeln!(out, "  {e}")?;
+                    continue;
+                }
+            };
+            for (i, line) in data.chunks(16).enumerate() {
+                let hex: Vec<_> = line.iter().map(|b| format!("{b:02x}")).collect();
+                writeln!(out, "  {:#010x}: {}", u64::from(address) + i as u64 * 16, hex.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute a single command. Returns `false` if the debugger should exit.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> anyhow::Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(command) = args.first() else {
+            return Ok(true);
+        };
+        let raw_arg = |index: usize, name: &str| {
+            args.get(index)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("Missing argument: {name}"))
+        };
+        let arg = |index: usize, name: &str| raw_arg(index, name).and_then(parse_number);
+        // optional counts and indices, negative values are rejected instead of wrapping around.
+        let optional_arg = |index: usize, usage: &str| match args.get(index) {
+            None => Ok(None),
+            Some(value) if value.starts_with('-') => anyhow::bail!("Invalid argument: {value}. Usage: {usage}"),
+            Some(value) => parse_number(value)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Invalid argument: {value} ({e}). Usage: {usage}")),
+        };
+
+        match *command {
+            "s" | "step" => {
+                let n = optional_arg(1, "step [n]")?.unwrap_or(1);
+                self.step(n as usize)?;
+                self.print_state(out)?;
+            }
+            "c" | "continue" => {
+                self.resume()?;
+                self.print_state(out)?;
+            }
+            "b" | "break" => {
+                let pc = arg(1, "pc")? as u32;
+                self.breakpoints.insert(pc);
+                writeln!(out, "Breakpoints: {:?}", self.breakpoints)?;
+            }
+            "d" | "delete" => {
+                let pc = arg(1, "pc")? as u32;
+                self.breakpoints.remove(&pc);
+                writeln!(out, "Breakpoints: {:?}", self.breakpoints)?;
+            }
+            "r" | "regs" => self.print_state(out)?,
+            "m" | "mem" => {
+                let address = arg(1, "address")? as u32;
+                let length = arg(2, "length")?;
+                anyhow::ensure!(
+                    length <= u64::from(MAX_MEMORY_LENGTH),
+                    "Length too large, at most {MAX_MEMORY_LENGTH} bytes can be dumped at once."
+                );
+                self.print_memory(out, address, length as u32)?;
+            }
+            "setreg" => {
+                let index = arg(1, "index")? as usize;
+                let value = arg(2, "value")?;
+                anyhow::ensure!(index < api::NUMBER_OF_REGISTERS, "Invalid register: {index}");
+                self.set_register(index, value);
+                self.print_state(out)?;
+            }
+            "setmem" => {
+                let address = arg(1, "address")? as u32;
+                let data = parse_hex(raw_arg(2, "hex")?)?;
+                self.write_memory(address, &data)?;
+                self.print_memory(out, address, data.len() as u32)?;
+            }
+            "gas" => {
+                let gas = arg(1, "value")? as i64;
+                self.set_gas(gas);
+                self.print_state(out)?;
+            }
+            "snapshot" => {
+                let snapshot = self
+                    .sessions
+                    .iter()
+                    .map(|s| s.pvm.snapshot())
+                    .collect::<api::Result<_>>()?;
+                self.snapshots.push(snapshot);
+                writeln!(out, "Saved snapshot {}", self.snapshots.len() - 1)?;
+            }
+            "rewind" => {
+                let index = optional_arg(1, "rewind [i]")?
+                    .map(|i| i as usize)
+                    .unwrap_or(self.snapshots.len().wrapping_sub(1));
+                let snapshot = self
+                    .snapshots
+                    .get(index)
+                    .ok_or_else(|| anyhow::anyhow!("No snapshot {index}"))?;
+                for (session, snapshot) in self.sessions.iter_mut().zip(snapshot) {
+                    session.pvm.restore(snapshot)?;
+                    session.status = None;
+                }
+                self.print_state(out)?;
+            }
+            "reset" => {
+                self.reset()?;
+                self.print_state(out)?;
+            }
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => writeln!(out, "{HELP}")?,
+            other => writeln!(out, "Unknown command: {other}. Type `help` for the list of commands.")?,
+        }
+        Ok(true)
+    }
+
+    /// Read commands from `input` until it's closed or `quit` is entered.
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> anyhow::Result<()> {
+        writeln!(
+            out,
+            "Debugging {}. Type `help` for the list of commands.",
+            self.json.name
+        )?;
+        self.print_state(&mut out)?;
+        write!(out, "(pvm) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            match self.execute(&line?, &mut out) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => writeln!(out, "Error: {e}")?,
+            }
+            write!(out, "(pvm) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Print a labeled row of values, marking rows where the PVMs disagree.
+fn row(out: &mut dyn Write, label: &str, values: Vec<String>) -> std::io::Result<()> {
+    write!(out, "{label:<8}")?;
+    let differs = values.windows(2).any(|w| w[0] != w[1]);
+    for v in &values {
+        write!(out, "{v:<COLUMN$}")?;
+    }
+    writeln!(out, "{}", if differs { " ≠" } else { "" })
+}
+
+pub(crate) fn parse_number(s: &str) -> anyhow::Result<u64> {
+    let value = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)?
+    } else if s.starts_with('-') {
+        s.parse::<i64>()? as u64
+    } else {
+        s.parse()?
+    };
+    Ok(value)
+}
+
+pub(crate) fn parse_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    let digits = s.trim_start_matches("0x").as_bytes();
+    anyhow::ensure!(digits.len().is_multiple_of(2), "Odd number of hex digits.");
+    let digit = |c: u8| {
+        char::from(c)
+            .to_digit(16)
+            .filter(|_| c.is_ascii())
+            .ok_or_else(|| anyhow::anyhow!("Invalid hex data: {s}"))
+    };
+    digits
+        .chunks_exact(2)
+        .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::reference::Reference;
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex("0x00ff1A").unwrap(), vec![0x00, 0xff, 0x1a]);
+        assert_eq!(parse_hex("").unwrap(), Vec::<u8>::new());
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("0xzz").is_err());
+        // multi-byte characters must not be split in the middle.
+        assert!(parse_hex("aéb").is_err());
+        assert!(parse_hex("éé").is_err());
+    }
+
+    #[test]
+    fn invalid_step_counts_are_rejected() {
+        let json = serde_json::from_str(
+            r#"{
+            "name": "trap", "initial-regs": [0,0,0,0,0,0,0,0,0,0,0,0,0], "initial-pc": 0,
+            "initial-page-map": [], "initial-memory": [], "initial-gas": 10, "program": [0, 0, 1, 0, 1],
+            "expected-status": "panic", "expected-regs": [0,0,0,0,0,0,0,0,0,0,0,0,0], "expected-pc": 0,
+            "expected-memory": [], "expected-gas": 9
+        }"#,
+        )
+        .unwrap();
+        let pvms: Vec<(String, Box<dyn PvmApi>)> = vec![("reference".into(), Box::new(Reference::default()))];
+        let mut debugger = Debugger::new(pvms, json).unwrap();
+        let mut out = vec![];
+        assert!(debugger.execute("step abc", &mut out).is_err());
+        assert!(debugger.execute("step -3", &mut out).is_err());
+        assert_eq!(debugger.state()[0].status, None);
+        assert!(debugger.execute("step", &mut out).unwrap());
+        assert_eq!(debugger.state()[0].status, Some(Status::Trap));
+    }
+}
@@ -0,0 +1,46 @@
+//! Gas boundary cases: a test case is run once to learn its gas cost, then again with the gas
+//! limit set exactly to the cost, just below it, to zero and to negative values. The PVMs must
+//! agree on whether they run out of gas, as well as on the remaining gas and pc when they do.
+
+use super::{check, Origin};
+use crate::{api::collection::PvmApiCollection, json::TestcaseJson};
+
+/// Initial gas values to check for a program with given cost.
+pub fn limits(cost: i64) -> Vec<i64> {
+    let mut limits = vec![cost, cost.saturating_sub(1), 0, -1, i64::MIN];
+    limits.sort();
+    limits.dedup();
+    limits
+}
+
+/// Test cases to check for given iteration.
+///
+/// If the cost can't be learned (the PVMs disagree, fail or run out of gas) the test case
+/// itself is returned, so that it's reported as usual.
+pub fn cases(
+    pvms: &mut PvmApiCollection,
+    origin: Origin,
+    case: TestcaseJson,
+    description: String,
+    corpus: &[TestcaseJson],
+) -> anyhow::Result<Vec<(Origin, TestcaseJson, String)>> {
+    let cost = match check(pvms, case.clone()) {
+        Ok((json, mismatches)) if mismatches.is_empty() && json.expected_status != "out-of-gas" => {
+            json.initial_gas - json.expected_gas
+        }
+        _ => return Ok(vec![(origin, case, description)]),
+    };
+    log::debug!("[fuzz] {} costs {cost} gas", case.name);
+
+    limits(cost)
+        .into_iter()
+        .map(|gas| {
+            let origin = Origin {
+                gas: Some(gas),
+                ..origin.clone()
+            };
+            let (case, description) = origin.testcase(corpus)?;
+            Ok((origin, case, description))
+        })
+        .collect()
+}
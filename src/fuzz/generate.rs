@@ -0,0 +1,278 @@
+//! Structure-aware generator of well-formed programs.
+//!
+//! A program is a sequence of basic blocks, each ending with a terminator. Jumps, branches and
+//! indirect jumps (through the jump table) only target the start of a later block, so every
+//! program terminates. The only backward edges are loops over a single block, which count down
+//! `LOOP_REGISTER` (set by the preceding block and not used by other instructions). The last
+//! block halts.
+
+use super::{mutate::BOUNDARY_VALUES, Rng};
+use crate::{
+    api::PAGE_SIZE,
+    instruction::{self, sign_extend_32, Args, Kind, Opcode, HALT_ADDRESS},
+    json::{MemoryChunk, Page, TestcaseJson},
+    program::GenericProgram,
+};
+
+pub const LOOP_REGISTER: u8 = 12;
+/// Start of the memory mapped by generated test cases: a writable page followed by a read-only one.
+pub const MEMORY: u32 = 0x20000;
+const MEMORY_LEN: u32 = 2 * PAGE_SIZE;
+const MAX_BLOCKS: usize = 8;
+const MAX_BLOCK_LEN: usize = 6;
+const MAX_LOOP_ITERATIONS: usize = 8;
+
+/// An instruction along with its jump target: the `Args::imms` slot and the index of the target block.
+struct Item {
+    opcode: &'static Opcode,
+    args: Args,
+    target: Option<(usize, usize)>,
+}
+
+pub fn op(name: &str) -> &'static Opcode {
+    instruction::OPCODES
+        .iter()
+        .find(|o| o.name == name)
+        .expect("the opcode exists; qed")
+}
+
+fn item(name: &str, regs: [u8; 3], imms: [u64; 2]) -> Item {
+    Item {
+        opcode: op(name),
+        args: Args { regs, imms },
+        target: None,
+    }
+}
+
+/// A random register other than `LOOP_REGISTER`.
+fn reg(rng: &mut Rng) -> u8 {
+    rng.below(LOOP_REGISTER as usize) as u8
+}
+
+/// A sign-extended 32-bit immediate, biased towards boundary values.
+fn imm(rng: &mut Rng) -> u64 {
+    let value = if rng.chance(50) {
+        *rng.pick(BOUNDARY_VALUES)
+    } else {
+        rng.next_u64()
+    };
+    sign_extend_32(value as u32)
+}
+
+/// An address in (or right next to) the mapped memory.
+fn address(rng: &mut Rng) -> u64 {
+    let offset = rng.below(MEMORY_LEN as usize + 16) as u64;
+    (u64::from(MEMORY) + offset).wrapping_sub(8)
+}
+
+/// A random non-terminator instruction.
+fn instruction(rng: &mut Rng) -> Item {
+    let candidates: Vec<_> = instruction::OPCODES.iter().filter(|o| !o.is_terminator()).collect();
+    let opcode = *rng.pick(&candidates);
+    let regs = [reg(rng), reg(rng), reg(rng)];
+    let mut imms = [imm(rng), imm(rng)];
+    match opcode.kind {
+        Kind::OneRegExtImm => imms[0] = rng.next_u64(),
+        // direct addressing.
+        Kind::TwoImm | Kind::OneRegOneImm if opcode.is_memory_access() && rng.chance(80) => imms[0] = address(rng),
+        _ => {}
+    }
+    Item {
+        opcode,
+        args: Args { regs, imms },
+        target: None,
+    }
+}
+
+/// Generate a random program.
+pub fn program(rng: &mut Rng) -> GenericProgram {
+    let count = 1 + rng.below(MAX_BLOCKS);
+    // blocks which are loop bodies (can only be entered from the preceding block).
+    let mut is_loop = vec![false; count];
+    for i in 1..count.saturating_sub(1) {
+        if !is_loop[i - 1] && rng.chance(20) {
+            is_loop[i] = true;
+        }
+    }
+    // blocks which can be jumped to and their index in the jump table.
+    let jump_table: Vec<usize> = (0..count).filter(|i| !is_loop[*i]).collect();
+
+    let mut blocks: Vec<Vec<Item>> = vec![];
+    for i in 0..count {
+        let mut block: Vec<_> = (0..rng.below(MAX_BLOCK_LEN + 1)).map(|_| instruction(rng)).collect();
+        let forward: Vec<_> = jump_table.iter().copied().filter(|j| *j > i).collect();
+        if i + 1 == count {
+            block.push(item("load_imm", [0, 0, 0], [sign_extend_32(HALT_ADDRESS), 0]));
+            block.push(item("jump_ind", [0, 0, 0], [0, 0]));
+        } else if is_loop[i] {
+            block.push(item("add_imm_64", [LOOP_REGISTER, LOOP_REGISTER, 0], [u64::MAX, 0]));
+            let mut branch = item("branch_ne_imm", [LOOP_REGISTER, 0, 0], [0, 0]);
+            branch.target = Some((1, i));
+            block.push(branch);
+        } else if is_loop[i + 1] {
+            let iterations = 1 + rng.below(MAX_LOOP_ITERATIONS) as u64;
+            block.push(item("load_imm", [LOOP_REGISTER, 0, 0], [iterations, 0]));
+            block.push(item("fallthrough", [0; 3], [0; 2]));
+        } else {
+            let target = *rng.pick(&forward);
+            match rng.below(6) {
+                0 => block.push(item("fallthrough", [0; 3], [0; 2])),
+                1 => block.push(item("trap", [0; 3], [0; 2])),
+                2 => {
+                    let mut jump = item("jump", [0; 3], [0; 2]);
+                    jump.target = Some((0, target));
+                    block.push(jump);
+                }
+                3 => {
+                    // jump table entry `k` is at address `(k + 1) * 2`.
+                    let index = jump_table.iter().position(|j| *j == target).unwrap_or_default();
+                    let register = reg(rng);
+                    block.push(item("load_imm", [register, 0, 0], [(index as u64 + 1) * 2, 0]));
+                    block.push(item("jump_ind", [register, 0, 0], [0, 0]));
+                }
+                4 => {
+                    let branches: Vec<_> = instruction::OPCODES
+                        .iter()
+                        .filter(|o| o.kind == Kind::TwoRegOneOffset)
+                        .collect();
+                    let opcode = *rng.pick(&branches);
+                    block.push(Item {
+                        opcode,
+                        args: Args {
+                            regs: [reg(rng), reg(rng), 0],
+                            imms: [0, 0],
+                        },
+                        target: Some((0, target)),
+                    });
+                }
+                _ => {
+                    let branches: Vec<_> = instruction::OPCODES
+                        .iter()
+                        .filter(|o| o.kind == Kind::OneRegImmOffset && o.name != "load_imm_jump")
+                        .collect();
+                    let opcode = *rng.pick(&branches);
+                    block.push(Item {
+                        opcode,
+                        args: Args {
+                            regs: [reg(rng), 0, 0],
+                            imms: [imm(rng), 0],
+                        },
+                        target: Some((1, target)),
+                    });
+                }
+            }
+        }
+        blocks.push(block);
+    }
+
+    // lengths don't depend on jump targets, so the layout can be computed first.
+    let mut starts = vec![];
+    let mut pc = 0u32;
+    for block in &blocks {
+        starts.push(pc);
+        for item in block {
+            pc += instruction::encode(item.opcode, &item.args, pc).len() as u32;
+        }
+    }
+
+    let mut program = GenericProgram {
+        jump_table: jump_table.iter().map(|i| starts[*i]).collect(),
+        ..Default::default()
+    };
+    for block in &mut blocks {
+        for item in block {
+            if let Some((slot, target)) = item.target {
+                item.args.imms[slot] = u64::from(starts[target]);
+            }
+            program.push(item.opcode, &item.args);
+        }
+    }
+    program
+}
+
+/// Generate a test case running a random program with the memory at `MEMORY` mapped.
+pub fn testcase(rng: &mut Rng, name: String) -> TestcaseJson {
+    let mut initial_regs = [0u64; 13];
+    for reg in &mut initial_regs {
+        *reg = match rng.below(3) {
+            0 => *rng.pick(BOUNDARY_VALUES),
+            1 => address(rng),
+            _ => rng.next_u64(),
+        };
+    }
+    let contents = (0..rng.below(64)).map(|_| rng.next_u64() as u8).collect();
+
+    TestcaseJson {
+        name,
+        initial_regs,
+        initial_page_map: vec![
+            Page {
+                address: MEMORY,
+                length: PAGE_SIZE,
+                is_writable: true,
+            },
+            Page {
+                address: MEMORY + PAGE_SIZE,
+                length: PAGE_SIZE,
+                is_writable: false,
+            },
+        ],
+        initial_memory: vec![MemoryChunk {
+            address: MEMORY + rng.below(PAGE_SIZE as usize - 64) as u32,
+            contents,
+        }],
+        initial_gas: 10_000,
+        program: program(rng).encode(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn programs_are_well_formed() {
+        for seed in 0..500 {
+            let program = program(&mut Rng::new(seed));
+            let parsed = GenericProgram::parse(&program.encode()).expect("the program can be parsed");
+            assert_eq!(parsed, program, "seed {seed}");
+
+            // walk the instructions, recording the basic block starts and jump targets.
+            assert_eq!(program.bitmask.len(), program.code.len(), "seed {seed}");
+            let mut block_starts = vec![0];
+            let mut targets = vec![];
+            let mut pc = 0;
+            let mut last = None;
+            while (pc as usize) < program.code.len() {
+                assert!(
+                    program.bitmask[pc as usize],
+                    "seed {seed}: {pc} is not an instruction start"
+                );
+                let skip = program.skip(pc);
+                let (opcode, args) = instruction::decode(&program.code, pc, skip)
+                    .unwrap_or_else(|| panic!("seed {seed}: invalid opcode at {pc}"));
+                pc += 1 + skip;
+                if opcode.is_terminator() {
+                    block_starts.push(pc);
+                }
+                match opcode.kind {
+                    Kind::OneOffset | Kind::TwoRegOneOffset => targets.push(args.imms[0] as u32),
+                    Kind::OneRegImmOffset => targets.push(args.imms[1] as u32),
+                    _ => {}
+                }
+                last = Some(opcode.name);
+            }
+            assert_eq!(pc as usize, program.code.len(), "seed {seed}");
+            // the program can't run past the end of the code.
+            assert_eq!(last, Some("jump_ind"), "seed {seed}");
+
+            for target in targets.iter().chain(&program.jump_table) {
+                assert!(
+                    block_starts.contains(target),
+                    "seed {seed}: {target} is not a basic block start"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Memory layout cases: a random mix of read-only, writable and unmapped pages, accessed by
+//! loads and stores of every width around page boundaries, address zero and the end of the
+//! address space (where the address wraps around).
+
+use super::{generate, Rng};
+use crate::{
+    api::{NUMBER_OF_REGISTERS, PAGE_SIZE},
+    instruction::{self, sign_extend_32, Args, Kind, Opcode, HALT_ADDRESS},
+    json::{MemoryChunk, Page, TestcaseJson},
+    program::GenericProgram,
+};
+
+/// Start of the pages which may be mapped.
+pub const MEMORY: u32 = 0x20000;
+const PAGES: u32 = 6;
+const MAX_ACCESSES: usize = 4;
+/// Bytes of initial memory at both ends of a mapped page.
+const CHUNK_LEN: usize = 16;
+
+/// An address which is likely to hit a boundary, for an access of up to 8 bytes.
+fn address(rng: &mut Rng) -> u32 {
+    let offset = rng.below(16) as u32;
+    match rng.below(4) {
+        0 => offset,
+        1 => u32::MAX - offset,
+        // the start of one of the pages or the end of the last one.
+        _ => {
+            let edge = MEMORY + PAGE_SIZE * rng.below(PAGES as usize + 1) as u32;
+            edge.wrapping_add(offset).wrapping_sub(8)
+        }
+    }
+}
+
+/// Instructions setting `register` to a base for the `address`, returns the offset to add to it.
+fn set_base(rng: &mut Rng, register: u8, address: u32, out: &mut Vec<(&'static Opcode, Args)>) -> u64 {
+    let offset = (rng.below(33) as u32).wrapping_sub(16);
+    let base = address.wrapping_sub(offset);
+    let (opcode, value) = if rng.chance(25) {
+        // the upper half has to be ignored when computing the address.
+        (generate::op("load_imm_64"), (rng.next_u64() << 32) | u64::from(base))
+    } else {
+        (generate::op("load_imm"), sign_extend_32(base))
+    };
+    out.push((
+        opcode,
+        Args {
+            regs: [register, 0, 0],
+            imms: [value, 0],
+        },
+    ));
+    sign_extend_32(offset)
+}
+
+/// Append a random load or store, preceded by the instructions setting its base register.
+fn access(rng: &mut Rng, out: &mut Vec<(&'static Opcode, Args)>) {
+    let candidates: Vec<_> = instruction::OPCODES.iter().filter(|o| o.is_memory_access()).collect();
+    let opcode = *rng.pick(&candidates);
+    let address = address(rng);
+    let value = rng.below(NUMBER_OF_REGISTERS) as u8;
+    let base = rng.below(NUMBER_OF_REGISTERS) as u8;
+    let args = match opcode.kind {
+        Kind::OneRegOneImm => Args {
+            regs: [value, 0, 0],
+            imms: [sign_extend_32(address), 0],
+        },
+        Kind::TwoImm => Args {
+            regs: [0; 3],
+            imms: [sign_extend_32(address), sign_extend_32(rng.next_u64() as u32)],
+        },
+        Kind::TwoRegOneImm => {
+            let offset = set_base(rng, base, address, out);
+            Args {
+                regs: [value, base, 0],
+                imms: [offset, 0],
+            }
+        }
+        Kind::OneRegTwoImm => {
+            let offset = set_base(rng, base, address, out);
+            Args {
+                regs: [base, 0, 0],
+                imms: [offset, sign_extend_32(rng.next_u64() as u32)],
+            }
+        }
+        _ => unreachable!("memory accesses have one of the above layouts; qed"),
+    };
+    out.push((opcode, args));
+}
+
+/// Generate a program doing a few random memory accesses and then halting.
+pub fn program(rng: &mut Rng) -> GenericProgram {
+    let mut instructions = vec![];
+    for _ in 0..1 + rng.below(MAX_ACCESSES) {
+        access(rng, &mut instructions);
+    }
+    instructions.push((
+        generate::op("load_imm"),
+        Args {
+            regs: [0; 3],
+            imms: [sign_extend_32(HALT_ADDRESS), 0],
+        },
+    ));
+    instructions.push((generate::op("jump_ind"), Args::default()));
+
+    let mut program = GenericProgram::default();
+    for (opcode, args) in instructions {
+        program.push(opcode, &args);
+    }
+    program
+}
+
+/// Generate a test case with randomly mapped pages at `MEMORY`.
+pub fn testcase(rng: &mut Rng, name: String) -> TestcaseJson {
+    let mut initial_page_map = vec![];
+    let mut initial_memory = vec![];
+    for page in 0..PAGES {
+        let address = MEMORY + page * PAGE_SIZE;
+        let is_writable = match rng.below(3) {
+            0 => continue,
+            1 => false,
+            _ => true,
+        };
+        initial_page_map.push(Page {
+            address,
+            length: PAGE_SIZE,
+            is_writable,
+        });
+        for offset in [0, PAGE_SIZE - CHUNK_LEN as u32] {
+            initial_memory.push(MemoryChunk {
+                address: address + offset,
+                contents: (0..CHUNK_LEN).map(|_| rng.next_u64() as u8).collect(),
+            });
+        }
+    }
+
+    let mut initial_regs = [0u64; NUMBER_OF_REGISTERS];
+    for reg in &mut initial_regs {
+        *reg = rng.next_u64();
+    }
+
+    TestcaseJson {
+        name,
+        initial_regs,
+        initial_page_map,
+        initial_memory,
+        initial_gas: 10_000,
+        program: program(rng).encode(),
+        ..Default::default()
+    }
+}
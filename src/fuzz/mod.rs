@@ -0,0 +1,307 @@
+//! Differential fuzzing: test cases are executed on all PVMs and the ones where the PVMs
+//! disagree are reported and saved as findings (one per triage bucket).
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    api::{
+        collection::{Mismatch, PvmApiCollection},
+        Isa, PvmApi,
+    },
+    bridge, config,
+    json::TestcaseJson,
+    runner,
+};
+
+pub mod gas;
+pub mod generate;
+pub mod memory;
+pub mod mutate;
+mod rng;
+pub mod triage;
+
+pub use rng::Rng;
+use triage::{Signature, Triage};
+
+/// Version of the test case generation. Bump it whenever the same seed starts producing different cases.
+pub const VERSION: u32 = 1;
+
+/// How the fuzzed test cases are created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Mutate test cases from the corpus.
+    Mutate,
+    /// Generate random well-formed programs.
+    Generate,
+    /// Run programs (from the corpus if given, generated otherwise) with gas limits around their cost.
+    Gas,
+    /// Generate loads and stores around the boundaries of randomly mapped pages.
+    Memory,
+}
+
+pub struct Options {
+    pub strategy: Strategy,
+    pub iterations: usize,
+    pub seed: u64,
+    /// Directory to write divergent test cases to.
+    pub output: PathBuf,
+    /// Instruction set width, overrides the one of the test cases.
+    pub isa: Option<Isa>,
+}
+
+/// Everything needed to recreate a fuzzed test case.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Origin {
+    pub version: u32,
+    pub strategy: Strategy,
+    pub seed: u64,
+    pub iteration: usize,
+    /// Name of the mutated corpus entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// Instruction set width override.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isa: Option<Isa>,
+    /// Initial gas override.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gas: Option<i64>,
+}
+
+impl Origin {
+    /// Recreate the test case, mutants are created from the `base` entry of the corpus.
+    ///
+    /// Returns the test case along with a description of how it was created.
+    pub fn testcase(&self, corpus: &[TestcaseJson]) -> anyhow::Result<(TestcaseJson, String)> {
+        let mut rng = Rng::for_iteration(self.seed, self.iteration);
+        let base = || {
+            let base = self.base.as_deref().unwrap_or_default();
+            corpus
+                .iter()
+                .find(|json| json.name == base)
+                .with_context(|| format!("{base:?} is not in the corpus."))
+        };
+        let (mut case, description) = match self.strategy {
+            Strategy::Mutate => {
+                let mut mutant = base()?.clone();
+                let name = mutant.name.split('~').next().unwrap_or_default();
+                mutant.name = format!("{name}~{}", self.iteration);
+                let mutations = mutate::mutate(&mut rng, &mut mutant);
+                (mutant, format!("{mutations:?}"))
+            }
+            Strategy::Gas if self.base.is_some() => (base()?.clone(), "corpus".to_string()),
+            Strategy::Generate | Strategy::Gas => {
+                let name = format!("generated~{}", self.iteration);
+                (generate::testcase(&mut rng, name), "generated".to_string())
+            }
+            Strategy::Memory => {
+                let name = format!("memory~{}", self.iteration);
+                (memory::testcase(&mut rng, name), "generated".to_string())
+            }
+        };
+        if let Some(isa) = self.isa {
+            case.isa = isa;
+        }
+        if let Some(gas) = self.gas {
+            case.name = format!("{}~gas{gas}", case.name);
+            case.initial_gas = gas;
+        }
+        Ok((case, description))
+    }
+}
+
+/// A divergent test case (with expectations from the trusted PVM) and its origin.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Finding {
+    #[serde(flatten)]
+    pub testcase: TestcaseJson,
+    pub fuzz: Origin,
+}
+
+/// Load test cases from given files and directories (searched recursively).
+pub fn load_corpus(paths: &[PathBuf]) -> anyhow::Result<Vec<TestcaseJson>> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            config::collect_json_files(path, &mut files)?;
+        } else {
+            files.push(path.clone());
+        }
+    }
+    files
+        .iter()
+        .map(|file| runner::load_testcase(file).with_context(|| format!("Invalid corpus entry: {}", file.display())))
+        .collect()
+}
+
+/// Execute the test case on all PVMs.
+///
+/// Returns the test case with expectations filled in from the trusted PVM, along with the
+/// values the PVMs disagreed on.
+pub fn check(pvms: &mut PvmApiCollection, json: TestcaseJson) -> anyhow::Result<(TestcaseJson, Vec<Mismatch>)> {
+    pvms.take_mismatches();
+    let json = bridge::execute(pvms, json)?;
+    Ok((json, pvms.take_mismatches()))
+}
+
+/// Create test cases according to the strategy and check them on all PVMs.
+///
+/// When mutating, divergent test cases are added to the corpus.
+/// With the gas strategy, every iteration checks multiple test cases.
+pub fn run(pvms: &mut PvmApiCollection, mut corpus: Vec<TestcaseJson>, options: &Options) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        options.strategy != Strategy::Mutate || !corpus.is_empty(),
+        "The corpus is empty."
+    );
+    std::fs::create_dir_all(&options.output).with_context(|| "Failed to create output directory.".to_string())?;
+
+    println!("Fuzzing with seed {} ({:?})...", options.seed, options.strategy);
+    let mut rng = Rng::new(options.seed);
+    let (mut findings, mut errors) = (0, 0);
+    let mut triage = Triage::default();
+    for iteration in 0..options.iterations {
+        let from_corpus = match options.strategy {
+            Strategy::Mutate => true,
+            Strategy::Generate | Strategy::Memory => false,
+            Strategy::Gas => !corpus.is_empty(),
+        };
+        let origin = Origin {
+            version: VERSION,
+            strategy: options.strategy,
+            seed: options.seed,
+            iteration,
+            base: from_corpus.then(|| rng.pick(&corpus).name.clone()),
+            isa: options.isa,
+            gas: None,
+        };
+        let (case, description) = origin.testcase(&corpus)?;
+        let cases = match options.strategy {
+            Strategy::Gas => gas::cases(pvms, origin, case, description, &corpus)?,
+            _ => vec![(origin, case, description)],
+        };
+
+        for (origin, case, description) in cases {
+            match check(pvms, case) {
+                Ok((_, mismatches)) if mismatches.is_empty() => {}
+                Ok((json, mismatches)) => {
+                    findings += 1;
+                    let signature = Signature::new(pvms, &json, &mismatches).expect("there are mismatches; qed");
+                    if !triage.add(signature.clone(), &json.name) {
+                        log::debug!("[fuzz] {} is a duplicate of: {signature}", json.name);
+                        continue;
+                    }
+                    report(&json.name, &description, &signature, &mismatches);
+                    let finding = Finding {
+                        testcase: json,
+                        fuzz: origin,
+                    };
+                    save(&options.output, &finding)?;
+                    if options.strategy == Strategy::Mutate {
+                        corpus.push(finding.testcase);
+                    }
+                }
+                Err(e) => {
+                    errors += 1;
+                    log::debug!("[fuzz] Skipping {iteration}: {e:?}");
+                }
+            }
+        }
+    }
+
+    println!(
+        "{} iterations: {findings} divergent ({} unique), {errors} failed to execute",
+        options.iterations,
+        triage.buckets().count()
+    );
+    if findings > 0 {
+        triage.print(&mut std::io::stdout())?;
+        let buckets: Vec<_> = triage.buckets().collect();
+        let path = options.output.join("triage.json");
+        serde_json::to_writer_pretty(File::create(path)?, &buckets)?;
+    }
+    Ok(())
+}
+
+/// Recreate a finding from its origin and check it on the PVMs again.
+///
+/// Mutants are recreated from their base, which is looked up in the `corpus` and among the
+/// other findings in the directory of the replayed one.
+pub fn replay(pvms: &mut PvmApiCollection, path: &Path, corpus: &[PathBuf]) -> anyhow::Result<()> {
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    let finding: Finding = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Invalid finding: {}", path.display()))?;
+    let origin = &finding.fuzz;
+    anyhow::ensure!(
+        origin.version == VERSION,
+        "The finding was created by generator version {}, but the current one is {VERSION}.",
+        origin.version
+    );
+
+    let mut candidates = vec![];
+    if origin.strategy == Strategy::Mutate {
+        candidates = load_corpus(corpus)?;
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let mut files = vec![];
+        config::collect_json_files(dir.unwrap_or(Path::new(".")), &mut files)?;
+        // other files (like the triage summary) are not test cases.
+        candidates.extend(files.iter().filter_map(|file| runner::load_testcase(file).ok()));
+    }
+    let (case, description) = origin.testcase(&candidates)?;
+    anyhow::ensure!(
+        without_expectations(&case) == without_expectations(&finding.testcase),
+        "The recreated test case doesn't match {}.",
+        path.display()
+    );
+
+    println!(
+        "Replaying {} (seed {}, iteration {})...",
+        case.name, origin.seed, origin.iteration
+    );
+    let (json, mismatches) = check(pvms, case)?;
+    match Signature::new(pvms, &json, &mismatches) {
+        None => {
+            println!("✅ {}: all PVMs agree", json.name);
+            Ok(())
+        }
+        Some(signature) => {
+            report(&json.name, &description, &signature, &mismatches);
+            anyhow::bail!("{} still diverges.", json.name)
+        }
+    }
+}
+
+/// The test case with the expectations cleared, i.e. only what determines the execution.
+fn without_expectations(json: &TestcaseJson) -> TestcaseJson {
+    TestcaseJson {
+        expected_status: String::new(),
+        expected_page_fault_address: None,
+        expected_regs: vec![],
+        expected_pc: 0,
+        expected_memory: vec![],
+        expected_gas: 0,
+        ..json.clone()
+    }
+}
+
+fn report(name: &str, description: &str, signature: &Signature, mismatches: &[Mismatch]) {
+    let fields: Vec<_> = mismatches.iter().map(|m| m.field.as_str()).collect();
+    println!(
+        "❌ {name} ({description}): {signature}, differing {}",
+        fields.join(", ")
+    );
+}
+
+/// Write the finding to `dir`. Names of mutants come from the corpus, so only a sanitized name is
+/// used for the file, the original one is kept in the JSON.
+fn save(dir: &Path, finding: &Finding) -> anyhow::Result<()> {
+    let path = dir.join(format!("{}.json", runner::sanitize_file_name(&finding.testcase.name)));
+    serde_json::to_writer_pretty(File::create(&path)?, finding)?;
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! Mutations of existing test cases, targeting edge cases which random programs rarely hit.
+
+use super::Rng;
+use crate::{api::PAGE_SIZE, json::TestcaseJson, program::GenericProgram};
+
+/// Register values around sign, width and page boundaries.
+pub const BOUNDARY_VALUES: &[u64] = &[
+    0,
+    1,
+    2,
+    31,
+    32,
+    63,
+    64,
+    0x7f,
+    0x80,
+    0xff,
+    0x7fff,
+    0x8000,
+    0xffff,
+    PAGE_SIZE as u64 - 1,
+    PAGE_SIZE as u64,
+    i32::MAX as u64,
+    i32::MIN as u32 as u64,
+    i32::MIN as i64 as u64,
+    u32::MAX as u64,
+    u32::MAX as u64 + 1,
+    i64::MAX as u64,
+    i64::MIN as u64,
+    u64::MAX - 1,
+    u64::MAX,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    /// Replace the opcode of a random instruction.
+    Instruction,
+    /// Change an argument byte of a random instruction.
+    Immediate,
+    /// Set a random register to a boundary value.
+    Register,
+    /// Move an initial memory chunk.
+    Memory,
+    /// Change the initial gas.
+    Gas,
+}
+
+impl Mutation {
+    pub const ALL: &'static [Mutation] = &[
+        Mutation::Instruction,
+        Mutation::Immediate,
+        Mutation::Register,
+        Mutation::Memory,
+        Mutation::Gas,
+    ];
+}
+
+/// Apply 1 to 3 random mutations to the test case. Returns the mutations that were applied.
+pub fn mutate(rng: &mut Rng, json: &mut TestcaseJson) -> Vec<Mutation> {
+    let count = 1 + rng.below(3);
+    (0..count)
+        .map(|_| {
+            let mutation = *rng.pick(Mutation::ALL);
+            if apply(rng, json, mutation) {
+                mutation
+            } else {
+                // registers can always be mutated.
+                apply(rng, json, Mutation::Register);
+                Mutation::Register
+            }
+        })
+        .collect()
+}
+
+/// Apply given mutation, returns `false` if it's not applicable to the test case.
+pub fn apply(rng: &mut Rng, json: &mut TestcaseJson, mutation: Mutation) -> bool {
+    match mutation {
+        Mutation::Instruction | Mutation::Immediate => {
+            let Some(mut program) = GenericProgram::parse(&json.program) else {
+                return false;
+            };
+            let is_start = mutation == Mutation::Instruction;
+            let candidates: Vec<_> = (0..program.code.len())
+                .filter(|i| program.bitmask[*i] == is_start)
+                .collect();
+            if candidates.is_empty() {
+                return false;
+            }
+            let byte = &mut program.code[*rng.pick(&candidates)];
+            *byte = match rng.below(5) {
+                0 => byte.wrapping_add(1),
+                1 => byte.wrapping_sub(1),
+                2 => *byte ^ (1 << rng.below(8)),
+                3 => *rng.pick(&[0x00, 0x7f, 0x80, 0xff]),
+                _ => rng.next_u64() as u8,
+            };
+            json.program = program.encode();
+        }
+        Mutation::Register => {
+            let index = rng.below(json.initial_regs.len());
+            json.initial_regs[index] = if json.initial_page_map.is_empty() || rng.chance(70) {
+                *rng.pick(BOUNDARY_VALUES)
+            } else {
+                // an address at the edge of a mapped page.
+                let page = rng.pick(&json.initial_page_map);
+                let offset = *rng.pick(&[-8i64, -1, 0, 1, 8]);
+                let edge = u64::from(page.address) + *rng.pick(&[0, u64::from(page.length)]);
+                edge.wrapping_add(offset as u64)
+            };
+        }
+        Mutation::Memory => {
+            if json.initial_memory.is_empty() {
+                return false;
+            }
+            let index = rng.below(json.initial_memory.len());
+            let chunk = &mut json.initial_memory[index];
+            let delta = *rng.pick(&[-(PAGE_SIZE as i64), -1, 1, PAGE_SIZE as i64]);
+            chunk.address = chunk.address.wrapping_add(delta as u32);
+        }
+        Mutation::Gas => {
+            let gas = json.initial_gas;
+            json.initial_gas = match rng.below(6) {
+                0 => 0,
+                1 => gas.saturating_sub(1),
+                2 => gas.saturating_add(1),
+                3 => gas / 2,
+                4 => -1,
+                _ => (rng.next_u64() % (gas.unsigned_abs() + 1)) as i64,
+            };
+        }
+    }
+    true
+}
@@ -0,0 +1,302 @@
+//! Deduplication of findings: divergences with the same signature are most likely caused by
+//! the same bug, so only one representative of each bucket is kept.
+//!
+//! The signature is computed by stepping through the test case to find the first instruction
+//! after which the PVMs disagree.
+
+use std::{collections::BTreeMap, fmt, io::Write};
+
+use crate::{
+    api::{
+        collection::{Mismatch, PvmApiCollection},
+        PvmApi, Status,
+    },
+    instruction,
+    json::TestcaseJson,
+    program::GenericProgram,
+    runner,
+};
+
+/// Fields in the order of significance, a divergence of the status usually causes the others to diverge.
+const FIELDS: &[&str] = &["status", "pc", "gas"];
+/// Maximal number of instructions executed when looking for the first divergence.
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+pub struct Signature {
+    /// The most significant field the PVMs disagree on.
+    pub field: String,
+    /// Groups of PVMs which agree with each other, e.g. `polkavm | other`.
+    pub pvms: String,
+    /// The pc of the first instruction after which the PVMs disagree, `None` if stepping didn't find it.
+    pub pc: Option<u32>,
+    /// Name of the instruction at `pc`.
+    pub opcode: String,
+}
+
+impl Signature {
+    /// Compute the signature of a finding from its mismatches by stepping through the test case again.
+    pub fn new(pvms: &mut PvmApiCollection, json: &TestcaseJson, mismatches: &[Mismatch]) -> Option<Self> {
+        let fields: Vec<_> = mismatches.iter().map(|m| m.field.as_str()).collect();
+        let (pc, mismatch) = match first_divergence(pvms, json, &fields) {
+            Some((pc, mismatch)) => (Some(pc), mismatch),
+            None => (None, mismatches.iter().min_by_key(|m| significance(&m.field))?.clone()),
+        };
+
+        let mut groups: Vec<(&str, Vec<&str>)> = vec![];
+        for (name, value) in &mismatch.values {
+            match groups.iter_mut().find(|(v, _)| *v == value.as_str()) {
+                Some((_, names)) => names.push(name.as_str()),
+                None => groups.push((value.as_str(), vec![name.as_str()])),
+            }
+        }
+        let pvms: Vec<_> = groups.iter().map(|(_, names)| names.join(", ")).collect();
+
+        let code = pc.and_then(|pc| GenericProgram::parse(&json.program)?.opcode_at(pc));
+        let opcode = match code {
+            Some(code) => instruction::opcode(code).map_or_else(|| format!("invalid ({code})"), |o| o.name.into()),
+            None => "-".into(),
+        };
+
+        Some(Self {
+            field: mismatch.field.clone(),
+            pvms: pvms.join(" | "),
+            pc,
+            opcode,
+        })
+    }
+
+    fn pc(&self) -> String {
+        self.pc.map_or_else(|| "-".into(), |pc| pc.to_string())
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {} {} ({})", self.field, self.pc(), self.opcode, self.pvms)
+    }
+}
+
+/// Step through the test case until the PVMs disagree on one of the `fields`.
+///
+/// Returns the pc of the instruction after which they disagree along with the most significant
+/// mismatch. Disagreements on other fields are ignored, since they don't show up in the result.
+fn first_divergence(pvms: &mut PvmApiCollection, json: &TestcaseJson, fields: &[&str]) -> Option<(u32, Mismatch)> {
+    runner::setup_testcase(pvms, json).ok()?;
+    pvms.take_mismatches();
+    let mut found = None;
+    for _ in 0..MAX_STEPS {
+        let Some(pc) = pvms.program_counter() else {
+            break;
+        };
+        let status = pvms.step();
+        pvms.gas();
+        pvms.registers();
+        pvms.program_counter();
+        for page in &json.initial_page_map {
+            let _ = pvms.read_memory(page.address, &mut vec![0u8; page.length as usize]);
+        }
+
+        let mismatch = pvms
+            .take_mismatches()
+            .into_iter()
+            .filter(|m| fields.contains(&m.field.as_str()))
+            .min_by_key(|m| significance(&m.field));
+        if let Some(mismatch) = mismatch {
+            found = Some((pc, mismatch));
+            break;
+        }
+        if !matches!(status, Ok(Status::Ok)) {
+            break;
+        }
+    }
+    pvms.take_mismatches();
+    found
+}
+
+/// `status`, `pc`, `gas`, then registers in order, then anything else.
+fn significance(field: &str) -> (usize, usize) {
+    if let Some(index) = FIELDS.iter().position(|f| *f == field) {
+        return (index, 0);
+    }
+    match field.strip_prefix('r').and_then(|n| n.parse().ok()) {
+        Some(register) => (FIELDS.len(), register),
+        None => (FIELDS.len() + 1, 0),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Bucket {
+    pub signature: Signature,
+    /// Name of the first test case which hit the bucket.
+    pub representative: String,
+    pub count: usize,
+}
+
+/// Findings grouped by their signature.
+#[derive(Debug, Default)]
+pub struct Triage {
+    buckets: BTreeMap<Signature, Bucket>,
+}
+
+impl Triage {
+    /// Add a finding. Returns `true` if it's the first one with this signature.
+    pub fn add(&mut self, signature: Signature, name: &str) -> bool {
+        let mut is_new = false;
+        let bucket = self.buckets.entry(signature.clone()).or_insert_with(|| {
+            is_new = true;
+            Bucket {
+                signature,
+                representative: name.to_string(),
+                count: 0,
+            }
+        });
+        bucket.count += 1;
+        is_new
+    }
+
+    pub fn buckets(&self) -> impl Iterator<Item = &Bucket> {
+        self.buckets.values()
+    }
+
+    /// Print the buckets as a table, most frequent first.
+    pub fn print(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let mut buckets: Vec<_> = self.buckets().collect();
+        buckets.sort_by_key(|b| std::cmp::Reverse(b.count));
+
+        writeln!(
+            out,
+            "{:>7}  {:<10} {:>8} {:<20} {:<40} representative",
+            "count", "field", "pc", "opcode", "pvms"
+        )?;
+        for b in buckets {
+            let s = &b.signature;
+            writeln!(
+                out,
+                "{:>7}  {:<10} {:>8} {:<20} {:<40} {}",
+                b.count,
+                s.field,
+                s.pc(),
+                s.opcode,
+                s.pvms,
+                b.representative
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{reference::Reference, MemoryAccess, ProgramContainer, NUMBER_OF_REGISTERS},
+        bridge,
+        fuzz::generate::op,
+        instruction::Args,
+    };
+
+    /// The reference PVM, which corrupts the 2nd register after executing the instruction at `pc`.
+    #[derive(Default)]
+    struct Buggy {
+        pvm: Reference,
+        pc: u32,
+    }
+
+    impl PvmApi for Buggy {
+        fn run(&mut self) -> crate::api::Result<Status> {
+            loop {
+                match self.step()? {
+                    Status::Ok => continue,
+                    status => return Ok(status),
+                }
+            }
+        }
+        fn step(&mut self) -> crate::api::Result<Status> {
+            let pc = self.pvm.program_counter();
+            let status = self.pvm.step()?;
+            if pc == Some(self.pc) {
+                let mut registers = self.pvm.registers();
+                registers[1] += 1;
+                self.pvm.set_registers(&registers);
+            }
+            Ok(status)
+        }
+        fn gas(&self) -> i64 {
+            self.pvm.gas()
+        }
+        fn set_gas(&mut self, gas: i64) {
+            self.pvm.set_gas(gas)
+        }
+        fn registers(&self) -> [u64; NUMBER_OF_REGISTERS] {
+            self.pvm.registers()
+        }
+        fn set_registers(&mut self, registers: &[u64; NUMBER_OF_REGISTERS]) {
+            self.pvm.set_registers(registers)
+        }
+        fn program_counter(&self) -> Option<u32> {
+            self.pvm.program_counter()
+        }
+        fn set_next_program_counter(&mut self, pc: u32) {
+            self.pvm.set_next_program_counter(pc)
+        }
+        fn set_program(&mut self, code: &[u8], container: ProgramContainer) -> crate::api::Result<()> {
+            self.pvm.set_program(code, container)
+        }
+        fn set_page(&mut self, page: u32, access: MemoryAccess) {
+            self.pvm.set_page(page, access)
+        }
+        fn read_memory(&self, address: u32, out: &mut [u8]) -> crate::api::Result<()> {
+            self.pvm.read_memory(address, out)
+        }
+        fn write_memory(&mut self, address: u32, data: &[u8]) -> crate::api::Result<()> {
+            self.pvm.write_memory(address, data)
+        }
+    }
+
+    #[test]
+    fn signature_points_at_the_first_divergence() {
+        let mut program = GenericProgram::default();
+        let load = |reg, value| Args {
+            regs: [reg, 0, 0],
+            imms: [value, 0],
+        };
+        program.push(op("load_imm"), &load(1, 1));
+        let buggy_pc = program.code.len() as u32;
+        program.push(op("load_imm"), &load(0, 2));
+        program.push(
+            op("add_imm_64"),
+            &Args {
+                regs: [2, 1, 0],
+                imms: [3, 0],
+            },
+        );
+        program.push(op("trap"), &Args::default());
+        let json = TestcaseJson {
+            name: "buggy".into(),
+            initial_gas: 100,
+            program: program.encode(),
+            ..Default::default()
+        };
+
+        let buggy = Buggy {
+            pc: buggy_pc,
+            ..Default::default()
+        };
+        let mut pvms = PvmApiCollection::new(vec![
+            ("reference".into(), Box::new(Reference::default())),
+            ("buggy".into(), Box::new(buggy)),
+        ]);
+        let json = bridge::execute(&mut pvms, json).unwrap();
+        let mismatches = pvms.take_mismatches();
+        // the register is only corrupted after it was last written, but the divergence spreads to r2.
+        let fields: Vec<_> = mismatches.iter().map(|m| m.field.as_str()).collect();
+        assert_eq!(fields, ["r1", "r2"]);
+
+        let signature = Signature::new(&mut pvms, &json, &mismatches).unwrap();
+        assert_eq!(signature.field, "r1");
+        assert_eq!(signature.pc, Some(buggy_pc));
+        assert_eq!(signature.opcode, "load_imm");
+        assert_eq!(signature.pvms, "reference | buggy");
+    }
+}
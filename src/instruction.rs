@@ -0,0 +1,398 @@
+//! PVM instruction set (gray paper appendix A): opcodes, their argument layouts, encoding and decoding.
+
+use crate::api::NUMBER_OF_REGISTERS;
+
+/// Dynamic jumps to this address halt the program.
+pub const HALT_ADDRESS: u32 = 0xffff_0000;
+
+/// Sign-extend a 32-bit value, as done for immediates and results of 32-bit instructions.
+pub fn sign_extend_32(value: u32) -> u64 {
+    value as i32 as i64 as u64
+}
+
+/// Layout of the instruction arguments and the meaning of `Args` fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    NoArgs,
+    /// `imms[0]`.
+    OneImm,
+    /// `regs[0]`, 64-bit `imms[0]`.
+    OneRegExtImm,
+    /// `imms[0]`, `imms[1]`.
+    TwoImm,
+    /// Jump target in `imms[0]`.
+    OneOffset,
+    /// `regs[0]`, `imms[0]`.
+    OneRegOneImm,
+    /// `regs[0]`, `imms[0]`, `imms[1]`.
+    OneRegTwoImm,
+    /// `regs[0]`, `imms[0]`, jump target in `imms[1]`.
+    OneRegImmOffset,
+    /// Destination `regs[0]`, source `regs[1]`.
+    TwoReg,
+    /// `regs[0]` (A), `regs[1]` (B), `imms[0]`.
+    TwoRegOneImm,
+    /// `regs[0]` (A), `regs[1]` (B), jump target in `imms[0]`.
+    TwoRegOneOffset,
+    /// `regs[0]` (A), `regs[1]` (B), `imms[0]`, `imms[1]`.
+    TwoRegTwoImm,
+    /// `regs[0]` (A), `regs[1]` (B), destination `regs[2]` (D).
+    ThreeReg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opcode {
+    pub code: u8,
+    pub name: &'static str,
+    pub kind: Kind,
+}
+
+macro_rules! opcodes {
+    ($($kind:ident { $($code:literal $name:ident),* $(,)? })*) => {
+        /// All opcodes of the instruction set.
+        pub const OPCODES: &[Opcode] = &[
+            $($(Opcode { code: $code, name: stringify!($name), kind: Kind::$kind },)*)*
+        ];
+    };
+}
+
+opcodes! {
+    NoArgs { 0 trap, 1 fallthrough }
+    OneImm { 10 ecalli }
+    OneRegExtImm { 20 load_imm_64 }
+    TwoImm { 30 store_imm_u8, 31 store_imm_u16, 32 store_imm_u32, 33 store_imm_u64 }
+    OneOffset { 40 jump }
+    OneRegOneImm {
+        50 jump_ind, 51 load_imm,
+        52 load_u8, 53 load_i8, 54 load_u16, 55 load_i16, 56 load_u32, 57 load_i32, 58 load_u64,
+        59 store_u8, 60 store_u16, 61 store_u32, 62 store_u64,
+    }
+    OneRegTwoImm { 70 store_imm_ind_u8, 71 store_imm_ind_u16, 72 store_imm_ind_u32, 73 store_imm_ind_u64 }
+    OneRegImmOffset {
+        80 load_imm_jump,
+        81 branch_eq_imm, 82 branch_ne_imm, 83 branch_lt_u_imm, 84 branch_le_u_imm, 85 branch_ge_u_imm,
+        86 branch_gt_u_imm, 87 branch_lt_s_imm, 88 branch_le_s_imm, 89 branch_ge_s_imm, 90 branch_gt_s_imm,
+    }
+    TwoReg {
+        100 move_reg, 101 sbrk,
+        102 count_set_bits_64, 103 count_set_bits_32, 104 leading_zero_bits_64, 105 leading_zero_bits_32,
+        106 trailing_zero_bits_64, 107 trailing_zero_bits_32,
+        108 sign_extend_8, 109 sign_extend_16, 110 zero_extend_16, 111 reverse_bytes,
+    }
+    TwoRegOneImm {
+        120 store_ind_u8, 121 store_ind_u16, 122 store_ind_u32, 123 store_ind_u64,
+        124 load_ind_u8, 125 load_ind_i8, 126 load_ind_u16, 127 load_ind_i16, 128 load_ind_u32, 129 load_ind_i32,
+        130 load_ind_u64,
+        131 add_imm_32, 132 and_imm, 133 xor_imm, 134 or_imm, 135 mul_imm_32, 136 set_lt_u_imm, 137 set_lt_s_imm,
+        138 shlo_l_imm_32, 139 shlo_r_imm_32, 140 shar_r_imm_32, 141 neg_add_imm_32, 142 set_gt_u_imm,
+        143 set_gt_s_imm, 144 shlo_l_imm_alt_32, 145 shlo_r_imm_alt_32, 146 shar_r_imm_alt_32,
+        147 cmov_iz_imm, 148 cmov_nz_imm,
+        149 add_imm_64, 150 mul_imm_64, 151 shlo_l_imm_64, 152 shlo_r_imm_64, 153 shar_r_imm_64,
+        154 neg_add_imm_64, 155 shlo_l_imm_alt_64, 156 shlo_r_imm_alt_64, 157 shar_r_imm_alt_64,
+        158 rot_r_64_imm, 159 rot_r_64_imm_alt, 160 rot_r_32_imm, 161 rot_r_32_imm_alt,
+    }
+    TwoRegOneOffset {
+        170 branch_eq, 171 branch_ne, 172 branch_lt_u, 173 branch_lt_s, 174 branch_ge_u, 175 branch_ge_s,
+    }
+    TwoRegTwoImm { 180 load_imm_jump_ind }
+    ThreeReg {
+        190 add_32, 191 sub_32, 192 mul_32, 193 div_u_32, 194 div_s_32, 195 rem_u_32, 196 rem_s_32,
+        197 shlo_l_32, 198 shlo_r_32, 199 shar_r_32,
+        200 add_64, 201 sub_64, 202 mul_64, 203 div_u_64, 204 div_s_64, 205 rem_u_64, 206 rem_s_64,
+        207 shlo_l_64, 208 shlo_r_64, 209 shar_r_64,
+        210 and, 211 xor, 212 or, 213 mul_upper_s_s, 214 mul_upper_u_u, 215 mul_upper_s_u,
+        216 set_lt_u, 217 set_lt_s, 218 cmov_iz, 219 cmov_nz,
+        220 rot_l_64, 221 rot_l_32, 222 rot_r_64, 223 rot_r_32,
+        224 and_inv, 225 or_inv, 226 xnor, 227 max, 228 max_u, 229 min, 230 min_u,
+    }
+}
+
+/// Look up an opcode.
+pub fn opcode(code: u8) -> Option<&'static Opcode> {
+    OPCODES.iter().find(|o| o.code == code)
+}
+
+impl Opcode {
+    /// Whether the instruction ends a basic block.
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self.kind,
+            Kind::NoArgs | Kind::OneOffset | Kind::OneRegImmOffset | Kind::TwoRegOneOffset | Kind::TwoRegTwoImm
+        ) || self.name == "jump_ind"
+    }
+
+    /// Loads from memory (`load_imm*` instructions don't access it).
+    pub fn is_load(&self) -> bool {
+        self.name.starts_with("load_") && !self.name.starts_with("load_imm")
+    }
+
+    pub fn is_store(&self) -> bool {
+        self.name.starts_with("store_")
+    }
+
+    pub fn is_memory_access(&self) -> bool {
+        self.is_load() || self.is_store()
+    }
+}
+
+/// Decoded instruction arguments, see `Kind` for which of them are used.
+///
+/// Immediates are sign-extended to 64 bits, jump targets are absolute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Args {
+    pub regs: [u8; 3],
+    pub imms: [u64; 2],
+}
+
+/// Encode the instruction (opcode followed by the arguments) placed at `pc`.
+///
+/// Immediates are encoded with the minimal number of bytes, so they have to be sign-extended
+/// 32-bit values (except for `OneRegExtImm`). Jump targets always take 4 bytes, so that the
+/// length of the instruction doesn't depend on them.
+pub fn encode(opcode: &Opcode, args: &Args, pc: u32) -> Vec<u8> {
+    let [a, b, c] = args.regs.map(|r| r.min(NUMBER_OF_REGISTERS as u8 - 1));
+    let [x, y] = args.imms;
+    let offset = |target: u64| (target as u32).wrapping_sub(pc).to_le_bytes();
+
+    let mut out = vec![opcode.code];
+    match opcode.kind {
+        Kind::NoArgs => {}
+        Kind::OneImm => out.extend(compact(x)),
+        Kind::OneRegExtImm => {
+            out.push(a);
+            out.extend(x.to_le_bytes());
+        }
+        Kind::TwoImm => {
+            let x = compact(x);
+            out.push(x.len() as u8);
+            out.extend(x);
+            out.extend(compact(y));
+        }
+        Kind::OneOffset => out.extend(offset(x)),
+        Kind::OneRegOneImm => {
+            out.push(a);
+            out.extend(compact(x));
+        }
+        Kind::OneRegTwoImm | Kind::OneRegImmOffset => {
+            let x = compact(x);
+            out.push(a | (x.len() as u8) << 4);
+            out.extend(x);
+            match opcode.kind {
+                Kind::OneRegTwoImm => out.extend(compact(y)),
+                _ => out.extend(offset(y)),
+            }
+        }
+        Kind::TwoReg => out.push(a | b << 4),
+        Kind::TwoRegOneImm => {
+            out.push(a | b << 4);
+            out.extend(compact(x));
+        }
+        Kind::TwoRegOneOffset => {
+            out.push(a | b << 4);
+            out.extend(offset(x));
+        }
+        Kind::TwoRegTwoImm => {
+            let x = compact(x);
+            out.push(a | b << 4);
+            out.push(x.len() as u8);
+            out.extend(x);
+            out.extend(compact(y));
+        }
+        Kind::ThreeReg => {
+            out.push(a | b << 4);
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Decode the arguments of the instruction at `pc` (gray paper appendix A.5).
+///
+/// `skip` is the number of bytes following the opcode (see `GenericProgram::skip`), bytes past
+/// the end of the code are zeroes. Returns `None` for unknown opcodes.
+pub fn decode(code: &[u8], pc: u32, skip: u32) -> Option<(&'static Opcode, Args)> {
+    let byte = |i: usize| code.get(pc as usize + i).copied().unwrap_or(0);
+    let bytes = |start: usize, len: usize| (start..start + len).map(byte).collect::<Vec<_>>();
+    let imm = |start: usize, len: usize| sign_extend(&bytes(start, len));
+    let offset = |start: usize, len: usize| u64::from(pc.wrapping_add(imm(start, len) as u32));
+    let reg = |value: u8| value.min(NUMBER_OF_REGISTERS as u8 - 1);
+    let len = |value: usize| value.min(4);
+    let skip = skip as usize;
+
+    let opcode = opcode(byte(0))?;
+    let (low, high) = (reg(byte(1) % 16), reg(byte(1) / 16));
+    let mut args = Args::default();
+    match opcode.kind {
+        Kind::NoArgs => {}
+        Kind::OneImm => args.imms[0] = imm(1, len(skip)),
+        Kind::OneRegExtImm => {
+            args.regs[0] = low;
+            args.imms[0] = u64::from_le_bytes(bytes(2, 8).try_into().expect("8 bytes; qed"));
+        }
+        Kind::TwoImm => {
+            let lx = len(usize::from(byte(1) % 8));
+            let ly = len(skip.saturating_sub(lx + 1));
+            args.imms = [imm(2, lx), imm(2 + lx, ly)];
+        }
+        Kind::OneOffset => args.imms[0] = offset(1, len(skip)),
+        Kind::OneRegOneImm => {
+            args.regs[0] = low;
+            args.imms[0] = imm(2, len(skip.saturating_sub(1)));
+        }
+        Kind::OneRegTwoImm | Kind::OneRegImmOffset => {
+            let lx = len(usize::from(byte(1) / 16 % 8));
+            let ly = len(skip.saturating_sub(lx + 1));
+            args.regs[0] = low;
+            args.imms[0] = imm(2, lx);
+            args.imms[1] = match opcode.kind {
+                Kind::OneRegTwoImm => imm(2 + lx, ly),
+                _ => offset(2 + lx, ly),
+            };
+        }
+        Kind::TwoReg => args.regs = [low, high, 0],
+        Kind::TwoRegOneImm => {
+            args.regs = [low, high, 0];
+            args.imms[0] = imm(2, len(skip.saturating_sub(1)));
+        }
+        Kind::TwoRegOneOffset => {
+            args.regs = [low, high, 0];
+            args.imms[0] = offset(2, len(skip.saturating_sub(1)));
+        }
+        Kind::TwoRegTwoImm => {
+            let lx = len(usize::from(byte(2) % 8));
+            let ly = len(skip.saturating_sub(lx + 2));
+            args.regs = [low, high, 0];
+            args.imms = [imm(3, lx), imm(3 + lx, ly)];
+        }
+        Kind::ThreeReg => args.regs = [low, high, reg(byte(2))],
+    }
+    Some((opcode, args))
+}
+
+/// The shortest little-endian encoding of a sign-extended 32-bit value.
+fn compact(value: u64) -> Vec<u8> {
+    let bytes = (value as u32).to_le_bytes();
+    let len = (0..4).find(|len| sign_extend(&bytes[..*len]) == value).unwrap_or(4);
+    bytes[..len].to_vec()
+}
+
+/// Decode a little-endian number and sign-extend it to 64 bits (gray paper `X_n`).
+pub fn sign_extend(bytes: &[u8]) -> u64 {
+    let Some(last) = bytes.last() else {
+        return 0;
+    };
+    let fill = if last & 0x80 != 0 { 0xff } else { 0 };
+    let mut value = [fill; 8];
+    value[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sign-extended 32-bit immediates with encodings of every length.
+    const IMMEDIATES: &[u64] = &[
+        0,
+        1,
+        0x7f,
+        0x80,
+        0x7fff,
+        0x8000,
+        0x7f_ffff,
+        0x80_0000,
+        i32::MAX as u64,
+        i32::MIN as i64 as u64,
+        u64::MAX,
+        u64::MAX - 0x80,
+    ];
+    /// Jump targets, relative to `PC` they are both forward and backward.
+    const TARGETS: &[u64] = &[0, 99, 100, 101, 0x1_0000, u32::MAX as u64];
+    const PC: u32 = 100;
+
+    /// All argument combinations used by the kind (unused ones are zero).
+    fn arguments(kind: Kind) -> Vec<Args> {
+        let regs = |count: usize| {
+            let mut all = vec![];
+            for (a, b, c) in [(0, 1, 2), (12, 0, 5), (7, 12, 12)] {
+                let mut regs = [a, b, c];
+                regs[count..].fill(0);
+                all.push(regs);
+            }
+            all
+        };
+        let imms = |xs: &[u64], ys: &[u64]| {
+            let mut all = vec![];
+            for x in xs {
+                for y in ys {
+                    all.push([*x, *y]);
+                }
+            }
+            all
+        };
+        let (regs, imms) = match kind {
+            Kind::NoArgs => (regs(0), imms(&[0], &[0])),
+            Kind::OneImm => (regs(0), imms(IMMEDIATES, &[0])),
+            Kind::OneRegExtImm => (regs(1), imms(&[0, u64::MAX, 0x1234_5678_9abc_def0, 1 << 63], &[0])),
+            Kind::TwoImm => (regs(0), imms(IMMEDIATES, IMMEDIATES)),
+            Kind::OneOffset => (regs(0), imms(TARGETS, &[0])),
+            Kind::OneRegOneImm => (regs(1), imms(IMMEDIATES, &[0])),
+            Kind::OneRegTwoImm => (regs(1), imms(IMMEDIATES, IMMEDIATES)),
+            Kind::OneRegImmOffset => (regs(1), imms(IMMEDIATES, TARGETS)),
+            Kind::TwoReg => (regs(2), imms(&[0], &[0])),
+            Kind::TwoRegOneImm => (regs(2), imms(IMMEDIATES, &[0])),
+            Kind::TwoRegOneOffset => (regs(2), imms(TARGETS, &[0])),
+            Kind::TwoRegTwoImm => (regs(2), imms(IMMEDIATES, IMMEDIATES)),
+            Kind::ThreeReg => (regs(3), imms(&[0], &[0])),
+        };
+        let mut all = vec![];
+        for regs in &regs {
+            for imms in &imms {
+                all.push(Args {
+                    regs: *regs,
+                    imms: *imms,
+                });
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn every_kind_round_trips() {
+        for opcode in OPCODES {
+            for args in arguments(opcode.kind) {
+                let encoded = encode(opcode, &args, PC);
+                // the instruction is placed at `PC` and followed by the next one.
+                let mut code = vec![0; PC as usize];
+                code.extend(&encoded);
+                code.push(0);
+                let skip = encoded.len() as u32 - 1;
+                let decoded = decode(&code, PC, skip);
+                assert_eq!(decoded, Some((opcode, args)), "{} {args:?}: {encoded:?}", opcode.name);
+            }
+        }
+    }
+
+    #[test]
+    fn immediates_are_compact() {
+        assert_eq!(compact(0), Vec::<u8>::new());
+        assert_eq!(compact(0x7f), [0x7f]);
+        assert_eq!(compact(0x80), [0x80, 0]);
+        assert_eq!(compact(u64::MAX), [0xff]);
+        assert_eq!(compact(sign_extend_32(0xffff_ff80)), [0x80]);
+        assert_eq!(compact(sign_extend_32(0x8000_0000)), [0, 0, 0, 0x80]);
+        for value in IMMEDIATES {
+            assert_eq!(sign_extend(&compact(*value)), *value);
+        }
+    }
+
+    #[test]
+    fn missing_bytes_are_zeroes() {
+        // `load_imm_64` at the end of the code.
+        let (opcode, args) = decode(&[20, 0x03, 0x11], 0, 2).unwrap();
+        assert_eq!(opcode.name, "load_imm_64");
+        assert_eq!(args.regs[0], 3);
+        assert_eq!(args.imms[0], 0x11);
+        assert_eq!(decode(&[255], 0, 0), None);
+    }
+}
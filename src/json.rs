@@ -1,4 +1,6 @@
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+use crate::api::Isa;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Page {
     pub address: u32,
@@ -6,17 +8,20 @@ pub struct Page {
     pub is_writable: bool,
 }
 
-#[derive(PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct MemoryChunk {
     pub address: u32,
     pub contents: Vec<u8>,
 }
 
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct TestcaseJson {
     pub name: String,
+    /// Instruction set width, 64-bit if not specified.
+    #[serde(default, skip_serializing_if = "Isa::is_default")]
+    pub isa: Isa,
     pub initial_regs: [u64; 13],
     pub initial_pc: u32,
     pub initial_page_map: Vec<Page>,
@@ -24,6 +29,9 @@ pub struct TestcaseJson {
     pub initial_gas: i64,
     pub program: Vec<u8>,
     pub expected_status: String,
+    /// Page-aligned address of the fault for `page-fault` status.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_page_fault_address: Option<u32>,
     pub expected_regs: Vec<u64>,
     pub expected_pc: u32,
     pub expected_memory: Vec<MemoryChunk>,
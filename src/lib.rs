@@ -0,0 +1,19 @@
+//! Test harness for running tests on multiple PVMs.
+//!
+//! Besides the `pvm-test-harness` binary, the crate can be used as a library, e.g. to run
+//! `TestcaseJson` vectors in-process against a custom `api::PvmApi` implementation
+//! (see [`runner`]).
+
+pub mod api;
+pub mod bench;
+pub mod bridge;
+pub mod config;
+pub mod debugger;
+pub mod fuzz;
+pub mod instruction;
+pub mod json;
+pub mod program;
+pub mod runner;
+pub mod server;
+pub mod trace;
+pub mod vectors;
@@ -1,99 +1,275 @@
 use anyhow::Context;
-use api::PvmApi;
 use clap::{Parser, Subcommand};
-use config::{read_config_file, Pvm};
-use json::TestcaseJson;
-use std::{path::PathBuf, process::Stdio};
-
-mod api;
-mod config;
-mod json;
+use pvm_test_harness::{
+    api::{
+        self,
+        collection::{Policy, PvmApiCollection},
+        stdin::Framing,
+        Isa, PvmApi,
+    },
+    bench, bridge,
+    config::{read_config_file, Profile, Pvm},
+    debugger::Debugger,
+    fuzz,
+    json::TestcaseJson,
+    runner::{self, init_pvms},
+    server::Server,
+    trace, vectors,
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
     let args = Args::parse();
+    let load_testcase = |file: &Path| -> anyhow::Result<TestcaseJson> {
+        let mut json = runner::load_testcase(file)?;
+        if let Some(isa) = args.isa {
+            json.isa = isa;
+        }
+        Ok(json)
+    };
 
     match args.sub {
-        Command::Json { files } => {
-            let pvm = with_config(args.config, args.pvm)?;
+        Command::Json { files, allow_mismatch } => {
+            let profile = load_profile(args.config, args.profile, args.pvm, args.policy)?;
+            let files = profile.test_files(files)?;
+            let pvm = profile.pvm;
+            let policy = profile.policy.unwrap_or_default();
+            if policy == Policy::ExpectedOnly {
+                // every PVM is checked against the expectations on its own.
+                let mut pvms = init_pvms(&pvm)?;
+                for file in files {
+                    let json = load_testcase(&file)?;
+
+                    println!("{} running on {} pvms...", json.name, pvm.len());
+                    let mut failed = vec![];
+                    for (name, pvm) in &mut pvms {
+                        if let Err(e) = runner::run_testcase(pvm.as_mut(), &json) {
+                            println!("❌ [{name}] {e:?}");
+                            failed.push(name.as_str());
+                        }
+                    }
+                    anyhow::ensure!(failed.is_empty(), "{} failed on {}", json.name, failed.join(", "));
+
+                    println!("{} ✅", json.name);
+                }
+                return Ok(());
+            }
             // intialize pvms
-            let mut pvms = api::collection::PvmApiCollection::new(init_pvms(&pvm)?);
+            let mut pvms = PvmApiCollection::new(init_pvms(&pvm)?).with_policy(policy)?;
 
             for file in files {
-                let json = std::fs::read(&file).with_context(|| "Failed to read JSON file.".to_string())?;
-                let json: TestcaseJson =
-                    serde_json::from_slice(&json).with_context(|| "Failed to parse JSON file.".to_string())?;
+                let json = load_testcase(&file)?;
 
                 println!("{} running on {} pvms...", json.name, pvm.len());
-                let mut registers = [0u64; api::NUMBER_OF_REGISTERS];
-                for (out, reg) in &mut registers.iter_mut().zip(&json.initial_regs) {
-                    *out = *reg;
+                let result = runner::run_testcase(&mut pvms, &json);
+                let mismatches = pvms.take_mismatches();
+                for mismatch in &mismatches {
+                    let wrong = mismatch.values.iter().filter(|(name, _)| mismatch.wrong.contains(name));
+                    for (name, value) in wrong {
+                        println!("❌ [{}] {name}: {value}", mismatch.field);
+                    }
                 }
-                pvms.set_gas(json.initial_gas);
-                pvms.set_registers(&registers);
-                pvms.set_next_program_counter(json.initial_pc);
-                // TODO [ToDr] setup memory?
-                pvms.set_program(&json.program, api::ProgramContainer::Generic)?;
-
-                let status = pvms.run()?;
-                let regs = pvms.registers();
-                let gas = pvms.gas();
-                let pc = pvms.program_counter();
-
-                assert_eq!(format!("{status}"), json.expected_status, "Mismatching status");
-                assert_eq!(gas, json.expected_gas, "Mismatching gas");
-                assert_eq!(pc, Some(json.expected_pc), "Mismatching pc");
-                assert_eq!(&regs, &*json.expected_regs, "Mismatching regs");
-                // TODO [ToDr] Compare memory
+                result.with_context(|| format!("{} failed", json.name))?;
+                anyhow::ensure!(
+                    allow_mismatch || mismatches.is_empty(),
+                    "{} failed: the PVMs disagree (use --allow-mismatch to only check the trusted results).",
+                    json.name
+                );
 
                 println!("{} ✅", json.name);
             }
             Ok(())
         }
-        Command::Fuzz { .. } => {
-            todo!();
+        Command::Bench {
+            files,
+            iterations,
+            warmup,
+            gas,
+        } => {
+            let pvm = load_profile(args.config, args.profile, args.pvm, args.policy)?.pvm;
+            let pvms = init_pvms(&pvm)?;
+            let options = bench::Options {
+                iterations,
+                warmup,
+                gas,
+                isa: args.isa,
+            };
+            bench::run(pvms, &files, &options)
         }
-    }
-}
+        Command::Trace {
+            output,
+            max_steps,
+            files,
+        } => {
+            let pvm = load_profile(args.config, args.profile, args.pvm, args.policy)?.pvm;
+            let mut pvms = init_pvms(&pvm)?;
+            std::fs::create_dir_all(&output).with_context(|| "Failed to create output directory.".to_string())?;
 
-fn init_pvms(pvm: &[Pvm]) -> anyhow::Result<Vec<Box<dyn PvmApi>>> {
-    if pvm.is_empty() {
-        anyhow::bail!("No PVMs specified. Make sure to start at least one.");
-    }
-
-    pvm.iter()
-        .map(|pvm| {
-            match pvm {
-                Pvm::PolkaVM => Ok(Box::new(api::polkavm::PolkaVm::default()) as Box<dyn PvmApi>),
-                Pvm::Stdin { name, binary } => {
-                    // spawn process
-                    let process = std::process::Command::new(binary)
-                        .stdin(Stdio::piped())
-                        .stdout(Stdio::piped())
-                        .stderr(Stdio::inherit())
-                        .spawn()
-                        .with_context(|| format!("Unable to start stdin pvm: {name:?}"))?;
-                    let stdin = process.stdin.unwrap();
-                    let stdout = process.stdout.unwrap();
-                    Ok(Box::new(api::stdin::JsonStdin::new(stdout, stdin)) as _)
+            for file in files {
+                let json = load_testcase(&file)?;
+                for (name, pvm) in &mut pvms {
+                    let trace = trace::record(pvm.as_mut(), name, &json, max_steps)
+                        .with_context(|| format!("[{name}] Failed to trace {}", json.name))?;
+                    let path = output.join(format!(
+                        "{}.{}.jsonl",
+                        runner::sanitize_file_name(&json.name),
+                        runner::sanitize_file_name(name)
+                    ));
+                    trace.write(std::io::BufWriter::new(File::create(&path)?))?;
+                    let truncated = if trace.header.truncated {
+                        " (truncated ⚠️)"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "[{name}] {}: {} steps{truncated} -> {}",
+                        json.name,
+                        trace.steps.len(),
+                        path.display()
+                    );
                 }
-                Pvm::JsonRpc { .. } => {
-                    anyhow::bail!("RPC pvm is not supported yet.")
+            }
+            Ok(())
+        }
+        Command::TraceDiff { a, b, context } => {
+            let a = trace::Trace::read(BufReader::new(File::open(&a)?))?;
+            let b = trace::Trace::read(BufReader::new(File::open(&b)?))?;
+            let Some(divergence) = trace::diff(&a, &b) else {
+                if a.header.truncated || b.header.truncated {
+                    anyhow::bail!(
+                        "Traces are identical for the first {} steps, but truncated (increase --max-steps).",
+                        a.steps.len()
+                    );
                 }
+                println!("Traces are identical ({} steps) ✅", a.steps.len());
+                return Ok(());
+            };
+
+            let (ha, hb) = (&a.header, &b.header);
+            println!("A: {} on {}, B: {} on {}", ha.testcase, ha.pvm, hb.testcase, hb.pvm);
+            for step in divergence.step.saturating_sub(context)..divergence.step {
+                println!("  #{step}: {}", serde_json::to_string(&a.steps[step])?);
             }
-        })
-        .collect()
-}
+            println!("❌ #{}: differing {}", divergence.step, divergence.fields.join(", "));
+            let show = |t: &trace::Trace| match t.steps.get(divergence.step) {
+                Some(step) => serde_json::to_string(step),
+                None => Ok("<end of trace>".to_string()),
+            };
+            println!("  A: {}", show(&a)?);
+            println!("  B: {}", show(&b)?);
+            anyhow::bail!("Traces diverge at step {}", divergence.step);
+        }
+        Command::Debug { file, breakpoints } => {
+            let pvm = load_profile(args.config, args.profile, args.pvm, args.policy)?.pvm;
+            let pvms = init_pvms(&pvm)?;
+            let json = load_testcase(&file)?;
 
-fn with_config(config: Option<PathBuf>, mut pvms: Vec<Pvm>) -> anyhow::Result<Vec<Pvm>> {
-    match config {
-        Some(path) => {
-            let mut config = read_config_file(&path).with_context(|| "Failed to read the config file.".to_string())?;
-            pvms.append(&mut config.pvm);
-            Ok(pvms)
+            let mut debugger = Debugger::new(pvms, json)?;
+            debugger.breakpoints_mut().extend(breakpoints);
+            debugger.repl(std::io::stdin().lock(), std::io::stdout())
+        }
+        Command::Serve {
+            address,
+            collection,
+            allow_origin,
+        } => {
+            let profile = load_profile(args.config, args.profile, args.pvm, args.policy)?;
+            let pvm = profile.pvm;
+            let pvms = if collection {
+                let policy = profile.policy.unwrap_or_default();
+                let pvms = PvmApiCollection::new(init_pvms(&pvm)?).with_policy(policy)?;
+                vec![("collection".to_string(), Box::new(pvms) as Box<dyn api::PvmApi>)]
+            } else {
+                init_pvms(&pvm)?
+            };
+            Server::new(pvms, &profile.tests).listen(&address, &allow_origin)
+        }
+        Command::Bridge { framing } => {
+            let profile = load_profile(args.config, args.profile, args.pvm, args.policy)?;
+            let mut pvm = profile.pvm;
+            if pvm.is_empty() {
+                pvm.push(Pvm::PolkaVM);
+            }
+            let mut pvms = init_pvms(&pvm)?;
+            let mut pvm = if pvms.len() == 1 {
+                pvms.remove(0).1
+            } else {
+                let policy = profile.policy.unwrap_or_default();
+                Box::new(PvmApiCollection::new(pvms).with_policy(policy)?)
+            };
+            bridge::serve(pvm.as_mut(), framing, std::io::stdin().lock(), std::io::stdout().lock())
         }
-        None => Ok(pvms),
+        Command::Fuzz {
+            strategy,
+            iterations,
+            seed,
+            output,
+            replay,
+            corpus,
+        } => {
+            let profile = load_profile(args.config, args.profile, args.pvm, args.policy)?;
+            // replaying mutants needs the corpus as well.
+            let needs_corpus = strategy == fuzz::Strategy::Mutate || replay.is_some();
+            let corpus = if corpus.is_empty() && needs_corpus {
+                profile.test_files(vec![])?
+            } else {
+                corpus
+            };
+            let policy = profile.policy.unwrap_or_default();
+            let mut pvms = PvmApiCollection::new(init_pvms(&profile.pvm)?).with_policy(policy)?;
+            if let Some(finding) = replay {
+                return fuzz::replay(&mut pvms, &finding, &corpus);
+            }
+            let corpus = fuzz::load_corpus(&corpus)?;
+            let seed = seed.unwrap_or_else(|| {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+                now.map(|d| d.as_nanos() as u64).unwrap_or_default()
+            });
+            let options = fuzz::Options {
+                strategy,
+                iterations,
+                seed,
+                output,
+                isa: args.isa,
+            };
+            fuzz::run(&mut pvms, corpus, &options)
+        }
+        Command::GenVectors { output } => {
+            let mut pvm = load_profile(args.config, args.profile, args.pvm, args.policy)?.pvm;
+            if pvm.is_empty() {
+                pvm.push(Pvm::PolkaVM);
+            }
+            let (name, mut reference) = init_pvms(&pvm[..1])?.remove(0);
+            println!("Generating test vectors with expectations from {name}...");
+            vectors::generate(reference.as_mut(), &output, args.isa)
+        }
+    }
+}
+
+/// Select the profile from the config file and add PVMs and the policy given on the command line.
+fn load_profile(
+    config: Option<PathBuf>,
+    profile: Option<String>,
+    pvms: Vec<Pvm>,
+    policy: Option<Policy>,
+) -> anyhow::Result<Profile> {
+    let mut selected = match config {
+        Some(path) => read_config_file(&path)
+            .with_context(|| "Failed to read the config file.".to_string())?
+            .profile(profile.as_deref())?,
+        None if profile.is_some() => anyhow::bail!("--profile requires a config file."),
+        None => Profile::default(),
+    };
+    selected.add_pvms(pvms)?;
+    if policy.is_some() {
+        selected.policy = policy;
     }
+    Ok(selected)
 }
 
 /// Run test harness for PVMs.
@@ -103,9 +279,19 @@ struct Args {
     /// toml config file.
     #[arg(short, long)]
     config: Option<PathBuf>,
+    /// Profile from the config file to use.
+    #[arg(short, long)]
+    profile: Option<String>,
     #[clap(help=PVM_HELP)]
     #[arg(long)]
     pvm: Vec<Pvm>,
+    /// Instruction set width, overrides the one specified in test cases.
+    #[arg(long)]
+    isa: Option<Isa>,
+    /// Which PVM to trust when their results differ: 'first', 'oracle=<name>', 'majority' or
+    /// 'expected-only' (overrides the config).
+    #[arg(long)]
+    policy: Option<Policy>,
     /// command to execute
     #[command(subcommand)]
     sub: Command,
@@ -116,11 +302,100 @@ struct Args {
 enum Command {
     /// Execute a JSON test case.
     Json {
-        /// JSON file to load
+        /// JSON files to load (in addition to test directories from the config).
         files: Vec<PathBuf>,
+        /// Pass tests where the PVMs disagree or some of them fail, if the trusted results are as expected.
+        #[arg(long)]
+        allow_mismatch: bool,
+    },
+    /// Measure performance of PVMs on given programs.
+    Bench {
+        /// Number of measured runs per program.
+        #[arg(short = 'n', long, default_value_t = 100)]
+        iterations: usize,
+        /// Number of discarded runs before measuring.
+        #[arg(long, default_value_t = 10)]
+        warmup: usize,
+        /// Initial gas for raw polkavm blobs (JSON files carry their own).
+        #[arg(long, default_value_t = 10_000_000)]
+        gas: i64,
+        /// JSON test cases or polkavm program blobs to run.
+        files: Vec<PathBuf>,
+    },
+    /// Record per-instruction execution traces of JSON test cases on each PVM.
+    Trace {
+        /// Directory to write the traces to.
+        #[arg(short, long, default_value = "traces")]
+        output: PathBuf,
+        /// Maximal number of instructions to trace.
+        #[arg(long, default_value_t = 1_000_000)]
+        max_steps: usize,
+        /// JSON files to load
+        files: Vec<PathBuf>,
+    },
+    /// Compare two traces and show the first divergence.
+    TraceDiff {
+        /// First trace file.
+        a: PathBuf,
+        /// Second trace file.
+        b: PathBuf,
+        /// Number of preceding steps to display.
+        #[arg(long, default_value_t = 5)]
+        context: usize,
+    },
+    /// Interactively step through a JSON test case on all PVMs side by side.
+    Debug {
+        /// Initial breakpoints (program counters).
+        #[arg(short, long = "break")]
+        breakpoints: Vec<u32>,
+        /// JSON file to load
+        file: PathBuf,
+    },
+    /// Expose the PVMs to a debugger UI as a JSON-RPC server over WebSocket.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, default_value = "127.0.0.1:9944")]
+        address: String,
+        /// Expose all PVMs as a single one, which compares their results.
+        #[arg(long)]
+        collection: bool,
+        /// Browser origins (e.g. `https://pvm.fluffylabs.dev`) allowed to connect besides local pages.
+        #[arg(long)]
+        allow_origin: Vec<String>,
+    },
+    /// Serve the stdin protocol on stdin/stdout using the configured PVMs (polkavm by default).
+    Bridge {
+        /// Message framing.
+        #[arg(long, value_enum, default_value_t = Framing::Json)]
+        framing: Framing,
+    },
+    /// Run differential fuzz testing on generated or mutated test cases.
+    Fuzz {
+        /// How to create the test cases.
+        #[arg(long, value_enum, default_value_t = fuzz::Strategy::Mutate)]
+        strategy: fuzz::Strategy,
+        /// Number of test cases to generate.
+        #[arg(short = 'n', long, default_value_t = 10_000)]
+        iterations: usize,
+        /// Seed of the random generator (random if not given).
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Directory to write divergent test cases to.
+        #[arg(short, long, default_value = "findings")]
+        output: PathBuf,
+        /// Recreate a saved finding from its seed and check it again instead of fuzzing.
+        #[arg(long, value_name = "FINDING")]
+        replay: Option<PathBuf>,
+        /// JSON test cases or directories to mutate (test directories from the config by default).
+        corpus: Vec<PathBuf>,
+    },
+    /// Generate single-instruction test vectors with expectations from the first PVM (polkavm by default).
+    GenVectors {
+        /// Directory to write the vectors to (in jamtestvectors layout).
+        #[arg(short, long, default_value = "vectors")]
+        output: PathBuf,
     },
-    /// Run fuzz testing.
-    Fuzz {},
 }
 
-const PVM_HELP: &str = "PVMs to run. Can be either 'polkavm', 'stdin=<path>' or jsonrpc=<endpoint>.";
+const PVM_HELP: &str =
+    "PVMs to run. Can be either 'polkavm', 'reference', 'stdin=<path>', 'ffi=<path>', 'wasm=<path>' or jsonrpc=<endpoint>.";
@@ -0,0 +1,225 @@
+//! Parsing of programs in the generic (gray paper `deblob`) container.
+//!
+//! The container is: `E(|j|) ++ E_1(z) ++ E(|c|) ++ E_z(j) ++ c ++ k`, where `j` is the jump
+//! table with `z`-byte entries, `c` is the code and `k` is the instruction bitmask packed
+//! least-significant bit first.
+
+use crate::instruction::{self, Args, Opcode};
+
+/// A program decoded from the generic container.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GenericProgram {
+    pub jump_table: Vec<u32>,
+    pub code: Vec<u8>,
+    /// `true` for every byte of `code` that starts an instruction.
+    pub bitmask: Vec<bool>,
+}
+
+impl GenericProgram {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut input = data;
+        let jump_table_len = read_varint(&mut input)? as usize;
+        let item_size = *take(&mut input, 1)?.first()? as usize;
+        let code_len = read_varint(&mut input)? as usize;
+
+        let jump_table = take(&mut input, jump_table_len.checked_mul(item_size)?)?
+            .chunks(item_size.max(1))
+            .take(jump_table_len)
+            .map(|entry| {
+                let mut bytes = [0u8; 4];
+                let len = entry.len().min(4);
+                bytes[..len].copy_from_slice(&entry[..len]);
+                u32::from_le_bytes(bytes)
+            })
+            .collect();
+        let code = take(&mut input, code_len)?.to_vec();
+        let bitmask = take(&mut input, code_len.div_ceil(8))?;
+        let bitmask = (0..code_len).map(|i| bitmask[i / 8] & (1 << (i % 8)) != 0).collect();
+
+        Some(Self {
+            jump_table,
+            code,
+            bitmask,
+        })
+    }
+
+    /// Encode the program back into the generic container.
+    pub fn encode(&self) -> Vec<u8> {
+        let max = self.jump_table.iter().copied().max().unwrap_or(0);
+        let item_size = if self.jump_table.is_empty() {
+            0
+        } else {
+            (4 - max.leading_zeros() as usize / 8).max(1)
+        };
+
+        let mut out = vec![];
+        write_varint(&mut out, self.jump_table.len() as u64);
+        out.push(item_size as u8);
+        write_varint(&mut out, self.code.len() as u64);
+        for entry in &self.jump_table {
+            out.extend_from_slice(&entry.to_le_bytes()[..item_size]);
+        }
+        out.extend_from_slice(&self.code);
+        let mut bitmask = vec![0u8; self.code.len().div_ceil(8)];
+        for (i, _) in self.bitmask.iter().enumerate().filter(|(_, is_start)| **is_start) {
+            bitmask[i / 8] |= 1 << (i % 8);
+        }
+        out.extend_from_slice(&bitmask);
+        out
+    }
+
+    /// Append an instruction to the code.
+    pub fn push(&mut self, opcode: &Opcode, args: &Args) {
+        let bytes = instruction::encode(opcode, args, self.code.len() as u32);
+        self.bitmask.push(true);
+        self.bitmask.resize(self.bitmask.len() + bytes.len() - 1, false);
+        self.code.extend(bytes);
+    }
+
+    /// Number of bytes between the instruction at `pc` and the next one, at most 24 (gray paper `skip`).
+    ///
+    /// Positions past the end of the code count as instruction starts.
+    pub fn skip(&self, pc: u32) -> u32 {
+        (0..24)
+            .find(|j| *self.bitmask.get(pc as usize + 1 + *j as usize).unwrap_or(&true))
+            .unwrap_or(24)
+    }
+
+    /// Opcode of the instruction starting at `pc`, if there is one.
+    pub fn opcode_at(&self, pc: u32) -> Option<u8> {
+        let pc = pc as usize;
+        if *self.bitmask.get(pc)? {
+            self.code.get(pc).copied()
+        } else {
+            None
+        }
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Some(head)
+}
+
+/// Decode a variable-length natural number (gray paper `E`).
+pub fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let first = *take(input, 1)?.first()?;
+    let len = first.leading_ones() as usize;
+    let rest = take(input, len)?;
+    let mut low = [0u8; 8];
+    low[..len].copy_from_slice(rest);
+    let low = u64::from_le_bytes(low);
+    if len == 8 {
+        return Some(low);
+    }
+    let high = u64::from(first) & (0xff >> (len + 1));
+    Some(high << (8 * len) | low)
+}
+
+/// Encode a variable-length natural number (gray paper `E`).
+pub fn write_varint(out: &mut Vec<u8>, value: u64) {
+    for len in 0..8 {
+        if value < 1 << (7 * (len + 1)) {
+            // `len` leading one bits followed by the high bits of the value.
+            let prefix = !(0xffu8 >> len);
+            out.push(prefix | (value >> (8 * len)) as u8);
+            out.extend_from_slice(&value.to_le_bytes()[..len]);
+            return;
+        }
+    }
+    out.push(0xff);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::OPCODES;
+
+    fn op(name: &str) -> &'static Opcode {
+        OPCODES.iter().find(|o| o.name == name).unwrap()
+    }
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut out = vec![];
+        write_varint(&mut out, value);
+        out
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(varint(0), [0]);
+        assert_eq!(varint(0x7f), [0x7f]);
+        assert_eq!(varint(0x80), [0x80, 0x80]);
+        assert_eq!(varint(0x3fff), [0xbf, 0xff]);
+        assert_eq!(varint(0x4000), [0xc0, 0x00, 0x40]);
+        assert_eq!(varint(u64::MAX), [0xff; 9]);
+
+        for bits in 0..64 {
+            for value in [(1u64 << bits) - 1, 1 << bits, (1 << bits) + 1] {
+                let encoded = varint(value);
+                let mut input = &encoded[..];
+                assert_eq!(read_varint(&mut input), Some(value), "{value}: {encoded:?}");
+                assert!(input.is_empty());
+                // truncated input
+                let mut input = &encoded[..encoded.len() - 1];
+                assert_eq!(read_varint(&mut input), None, "{value}: {encoded:?}");
+            }
+        }
+    }
+
+    fn program(jump_table: Vec<u32>) -> GenericProgram {
+        let mut program = GenericProgram {
+            jump_table,
+            ..Default::default()
+        };
+        let args = |regs, imms| Args { regs, imms };
+        program.push(op("load_imm_64"), &args([1, 0, 0], [u64::MAX, 0]));
+        program.push(op("fallthrough"), &Args::default());
+        program.push(op("add_64"), &args([1, 2, 3], [0, 0]));
+        program.push(op("store_imm_u32"), &args([0, 0, 0], [0x20000, 0x1234]));
+        program.push(op("trap"), &Args::default());
+        program
+    }
+
+    #[test]
+    fn encode_and_parse() {
+        for jump_table in [vec![], vec![0], vec![10, 300], vec![0x1_0000], vec![u32::MAX, 1]] {
+            let program = program(jump_table);
+            let encoded = program.encode();
+            assert_eq!(GenericProgram::parse(&encoded), Some(program.clone()));
+            // every truncation is rejected.
+            for len in 0..encoded.len() {
+                assert_eq!(GenericProgram::parse(&encoded[..len]), None, "{len}");
+            }
+        }
+
+        // one-byte jump table entries, a 2-byte code and its bitmask.
+        let program = GenericProgram::parse(&[1, 1, 2, 7, 0, 1, 0b11]).unwrap();
+        assert_eq!(program.jump_table, [7]);
+        assert_eq!(program.code, [0, 1]);
+        assert_eq!(program.bitmask, [true, true]);
+    }
+
+    #[test]
+    fn instructions() {
+        let program = program(vec![]);
+        // load_imm_64 takes 10 bytes, fallthrough 1, add_64 3 and store_imm_u32 7.
+        assert_eq!(program.skip(0), 9);
+        assert_eq!(program.skip(10), 0);
+        assert_eq!(program.skip(11), 2);
+        assert_eq!(program.skip(14), 6);
+        assert_eq!(program.opcode_at(0), Some(20));
+        assert_eq!(program.opcode_at(1), None);
+        assert_eq!(program.opcode_at(14), Some(32));
+        assert_eq!(program.opcode_at(20), None);
+        assert_eq!(program.opcode_at(21), Some(0));
+        assert_eq!(program.opcode_at(22), None);
+        // the last instruction is followed by the end of the code.
+        assert_eq!(program.skip(21), 0);
+    }
+}
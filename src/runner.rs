@@ -0,0 +1,228 @@
+//! Running `TestcaseJson` vectors against any `PvmApi` implementation.
+//!
+//! This is what the `json` subcommand uses, but it can also be called from a PVM's own
+//! `cargo test` to run jamtestvectors in-process:
+//!
+//! ```ignore
+//! let json = pvm_test_harness::runner::load_testcase("inst_add_32.json".as_ref())?;
+//! pvm_test_harness::runner::run_testcase(&mut MyPvm::default(), &json)?;
+//! ```
+
+use std::{
+    fs::File,
+    io::Read,
+    path::Path,
+    process::Stdio,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+use crate::{
+    api::{self, MemoryAccess, PvmApi},
+    config::{Pvm, Stderr},
+    json::{MemoryChunk, Page, TestcaseJson},
+};
+
+/// Start all PVMs described by the config, returns them along with their names.
+pub fn init_pvms(pvm: &[Pvm]) -> anyhow::Result<Vec<(String, Box<dyn PvmApi>)>> {
+    if pvm.is_empty() {
+        anyhow::bail!("No PVMs specified. Make sure to start at least one.");
+    }
+
+    let instances: Vec<Box<dyn PvmApi>> = pvm
+        .iter()
+        .map(|pvm| {
+            match pvm {
+                Pvm::PolkaVM => Ok(Box::new(api::polkavm::PolkaVm::default()) as Box<dyn PvmApi>),
+                Pvm::Reference => Ok(Box::new(api::reference::Reference::default()) as _),
+                Pvm::Stdin {
+                    name,
+                    binary,
+                    args,
+                    env,
+                    cwd,
+                    stderr,
+                    framing,
+                } => {
+                    // spawn process
+                    let mut command = std::process::Command::new(binary);
+                    command
+                        .args(args)
+                        .envs(env)
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped());
+                    if let Some(cwd) = cwd {
+                        command.current_dir(cwd);
+                    }
+                    match stderr {
+                        Stderr::Inherit => command.stderr(Stdio::inherit()),
+                        Stderr::File(path) => command.stderr(
+                            File::create(path).with_context(|| format!("Unable to create {}", path.display()))?,
+                        ),
+                        Stderr::Attach => command.stderr(Stdio::piped()),
+                    };
+                    let mut process = command
+                        .spawn()
+                        .with_context(|| format!("Unable to start stdin pvm: {name:?}"))?;
+
+                    let stdin = process.stdin.take().unwrap();
+                    let stdout = process.stdout.take().unwrap();
+                    let mut pvm = api::stdin::JsonStdin::with_framing(stdout, stdin, *framing);
+                    if let Some(mut stderr) = process.stderr.take() {
+                        let buffer = Arc::new(Mutex::new(vec![]));
+                        let captured = buffer.clone();
+                        std::thread::spawn(move || {
+                            let mut chunk = [0u8; 1024];
+                            while let Ok(len @ 1..) = stderr.read(&mut chunk) {
+                                captured.lock().unwrap().extend_from_slice(&chunk[..len]);
+                            }
+                        });
+                        pvm = pvm.with_stderr(buffer);
+                    }
+                    Ok(Box::new(pvm) as _)
+                }
+                Pvm::Ffi { name, library } => {
+                    let pvm =
+                        api::ffi::FfiPvm::load(library).with_context(|| format!("Unable to load ffi pvm: {name:?}"))?;
+                    Ok(Box::new(pvm) as _)
+                }
+                Pvm::Wasm { name, module } => {
+                    let pvm = api::wasm::WasmPvm::load(module)
+                        .with_context(|| format!("Unable to load wasm pvm: {name:?}"))?;
+                    Ok(Box::new(pvm) as _)
+                }
+                Pvm::JsonRpc { .. } => {
+                    anyhow::bail!("RPC pvm is not supported yet.")
+                }
+            }
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(pvm.iter().map(Pvm::name).zip(instances).collect())
+}
+
+/// Read and parse a `TestcaseJson` file.
+pub fn load_testcase(path: &Path) -> anyhow::Result<TestcaseJson> {
+    let json = std::fs::read(path).with_context(|| "Failed to read JSON file.".to_string())?;
+    serde_json::from_slice(&json).with_context(|| "Failed to parse JSON file.".to_string())
+}
+
+/// Make a name (e.g. of a test case or PVM) safe to use as part of a file name.
+///
+/// Characters other than alphanumerics, `-`, `_`, `~` and `.` (e.g. path separators) are replaced with `_`.
+pub fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "-_~.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Load the initial state of the test case into given PVM.
+pub fn setup_testcase(pvm: &mut dyn PvmApi, json: &TestcaseJson) -> api::Result<()> {
+    pvm.begin_testcase();
+    pvm.set_isa(json.isa)?;
+    pvm.set_program(&json.program, api::ProgramContainer::Generic)?;
+    pvm.set_gas(json.initial_gas);
+    pvm.set_registers(&json.initial_regs);
+    pvm.set_next_program_counter(json.initial_pc);
+    setup_memory(pvm, &json.initial_page_map, &json.initial_memory)
+}
+
+/// Map the pages and write initial memory contents.
+///
+/// Must be called after `set_program`, which resets the memory.
+pub fn setup_memory(pvm: &mut dyn PvmApi, pages: &[Page], memory: &[MemoryChunk]) -> api::Result<()> {
+    for page in pages {
+        let access = if page.is_writable {
+            MemoryAccess::Writeable
+        } else {
+            MemoryAccess::Readable
+        };
+        let start = page.address / api::PAGE_SIZE;
+        let end = (u64::from(page.address) + u64::from(page.length)).div_ceil(u64::from(api::PAGE_SIZE));
+        for index in u64::from(start)..end {
+            pvm.set_page(index as u32, access);
+        }
+    }
+    for chunk in memory {
+        pvm.write_memory(chunk.address, &chunk.contents)?;
+    }
+    Ok(())
+}
+
+/// Execute the test case and compare the results with expectations.
+///
+/// Returns an error describing the first mismatch, along with `PvmApi::diagnostics`.
+pub fn run_testcase(pvm: &mut dyn PvmApi, json: &TestcaseJson) -> anyhow::Result<()> {
+    check_testcase(pvm, json).map_err(|e| match pvm.diagnostics() {
+        Some(diagnostics) => e.context(diagnostics),
+        None => e,
+    })
+}
+
+fn check_testcase(pvm: &mut dyn PvmApi, json: &TestcaseJson) -> anyhow::Result<()> {
+    setup_testcase(pvm, json)?;
+
+    let status = pvm.run()?;
+    let regs = pvm.registers();
+    let gas = pvm.gas();
+    let pc = pvm.program_counter();
+
+    anyhow::ensure!(
+        format!("{status}") == json.expected_status,
+        "Mismatching status: {status} vs expected {}",
+        json.expected_status
+    );
+    if let Some(expected) = json.expected_page_fault_address {
+        anyhow::ensure!(
+            status.fault_address() == Some(expected),
+            "Mismatching page fault address: {:?} vs expected {expected}",
+            status.fault_address()
+        );
+    }
+    anyhow::ensure!(
+        gas == json.expected_gas,
+        "Mismatching gas: {gas} vs expected {}",
+        json.expected_gas
+    );
+    anyhow::ensure!(
+        pc == Some(json.expected_pc),
+        "Mismatching pc: {pc:?} vs expected {}",
+        json.expected_pc
+    );
+    anyhow::ensure!(
+        regs[..] == json.expected_regs[..],
+        "Mismatching regs: {regs:?} vs expected {:?}",
+        json.expected_regs
+    );
+    for chunk in &json.expected_memory {
+        let mut contents = vec![0u8; chunk.contents.len()];
+        pvm.read_memory(chunk.address, &mut contents)
+            .with_context(|| format!("Unable to read memory at {}", chunk.address))?;
+        anyhow::ensure!(
+            contents == chunk.contents,
+            "Mismatching memory at {}: {contents:?} vs expected {:?}",
+            chunk.address,
+            chunk.contents
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_are_sanitized() {
+        assert_eq!(sanitize_file_name("inst_add-32~7"), "inst_add-32~7");
+        assert_eq!(sanitize_file_name("../../etc/passwd"), ".._.._etc_passwd");
+        assert_eq!(sanitize_file_name("stdin=./bin/pvm"), "stdin_._bin_pvm");
+    }
+}
@@ -0,0 +1,528 @@
+//! JSON-RPC 2.0 server over WebSocket, letting a debugger UI drive the harness's PVMs.
+//!
+//! Every text message is a single request, e.g.
+//! `{"jsonrpc": "2.0", "id": 1, "method": "step", "params": {"count": 10}}`.
+//!
+//! Methods:
+//! - `pvms` - names of the exposed PVMs.
+//! - `load` - `{"testcase": <TestcaseJson>}` or `{"path": "<file>"}`, loads the test case into all PVMs.
+//!   Only files from the configured `tests` directories can be loaded by path.
+//! - `reset` - restart from the initial state of the loaded test case.
+//! - `step` - `{"count": n}` (default 1), execute n instructions.
+//! - `run` - execute until a breakpoint or the end of execution.
+//! - `state` - status, pc, gas and registers of all PVMs.
+//! - `setRegister` - `{"index": i, "value": v}`.
+//! - `setGas` - `{"gas": g}`.
+//! - `memory` - `{"address": a, "length": l}`, returns `[{"name", "data": "0x...", "mismatches"}]`
+//!   (or `"error"` instead of `"data"`).
+//!   At most `debugger::MAX_MEMORY_LENGTH` bytes can be read at once.
+//! - `writeMemory` - `{"address": a, "data": "0x..."}`.
+//! - `breakpoints` - list of breakpoints.
+//! - `setBreakpoints` - `{"pcs": [...]}`, replaces all breakpoints.
+//!
+//! Methods modifying the execution return the same value as `state`:
+//! `[{"name", "status", "faultAddress", "pc", "gas", "registers", "mismatches"}]`, where `status` is `null`
+//! if nothing was executed yet. `mismatches` lists the disagreements within a collection of PVMs
+//! (`serve --collection`) since the previous response: `[{"field", "values": [{"name", "value"}], "wrong"}]`. Registers and gas are sent as decimal strings, since JavaScript
+//! numbers can't represent all 64-bit values; numbers in params can be given either way
+//! (strings may also be hex).
+//!
+//! Browsers may only connect from local pages (`localhost`, `127.0.0.1` or `[::1]`) and explicitly
+//! allowed origins; the handshake is rejected for other `Origin` headers.
+
+use std::{
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request as HandshakeRequest, Response},
+    http::StatusCode,
+    Message,
+};
+
+use crate::{
+    api::{self, collection::Mismatch, PvmApi},
+    debugger::{self, Debugger},
+    json::TestcaseJson,
+    runner,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+impl From<api::Error> for RpcError {
+    fn from(e: api::Error) -> Self {
+        Self::new(SERVER_ERROR, e)
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// A number given either as JSON number or as a (decimal or hex) string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Unsigned(u64),
+    Signed(i64),
+    Text(String),
+}
+
+impl Number {
+    fn value(&self) -> Result<u64, RpcError> {
+        match self {
+            Number::Unsigned(v) => Ok(*v),
+            Number::Signed(v) => Ok(*v as u64),
+            Number::Text(s) => debugger::parse_number(s).map_err(|e| RpcError::new(INVALID_PARAMS, e)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LoadParams {
+    testcase: Option<TestcaseJson>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct StepParams {
+    count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SetRegisterParams {
+    index: usize,
+    value: Number,
+}
+
+#[derive(Deserialize)]
+struct SetGasParams {
+    gas: Number,
+}
+
+#[derive(Deserialize)]
+struct MemoryParams {
+    address: u32,
+    length: u32,
+}
+
+#[derive(Deserialize)]
+struct WriteMemoryParams {
+    address: u32,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct BreakpointsParams {
+    pcs: Vec<u32>,
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+/// Whether the WebSocket handshake with given `Origin` header should be accepted.
+///
+/// Requests without the header don't come from a browser, so there is nothing to protect against.
+fn is_allowed_origin(origin: Option<&str>, allowed: &[String]) -> bool {
+    let Some(origin) = origin else {
+        return true;
+    };
+    let host = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+    let host = host.split('/').next().unwrap_or_default();
+    let is_local = ["localhost", "127.0.0.1", "[::1]"]
+        .iter()
+        .any(|local| host == *local || host.strip_prefix(local).is_some_and(|port| port.starts_with(':')));
+    is_local || allowed.iter().any(|a| a.trim_end_matches('/') == origin)
+}
+
+pub struct Server {
+    debugger: Debugger,
+    loaded: bool,
+    /// Canonical paths of directories from which test cases can be loaded by path.
+    tests: Vec<PathBuf>,
+}
+
+impl Server {
+    pub fn new(pvms: Vec<(String, Box<dyn PvmApi>)>, tests: &[PathBuf]) -> Self {
+        Self {
+            debugger: Debugger::without_testcase(pvms),
+            loaded: false,
+            // NOTE: missing directories are already reported by the config validation.
+            tests: tests.iter().filter_map(|dir| dir.canonicalize().ok()).collect(),
+        }
+    }
+
+    /// Accept connections on given address (e.g. `127.0.0.1:9944`) and serve them one at a time.
+    ///
+    /// Browser connections are only accepted from local pages and `allowed_origins`.
+    pub fn listen(&mut self, address: &str, allowed_origins: &[String]) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        println!("Listening on ws://{}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            log::info!("[serve] {peer} connected");
+            match self.connection(stream, allowed_origins) {
+                Ok(()) => log::info!("[serve] {peer} disconnected"),
+                Err(e) => log::error!("[serve] {peer}: {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    fn connection(&mut self, stream: TcpStream, allowed_origins: &[String]) -> anyhow::Result<()> {
+        // NOTE: the error type is dictated by `tungstenite`.
+        #[allow(clippy::result_large_err)]
+        let check_origin = |request: &HandshakeRequest, response: Response| {
+            let origin = request
+                .headers()
+                .get("origin")
+                .map(|o| o.to_str().unwrap_or("<invalid>"));
+            if is_allowed_origin(origin, allowed_origins) {
+                return Ok(response);
+            }
+            let mut error = ErrorResponse::new(Some(format!("Origin not allowed: {}", origin.unwrap_or_default())));
+            *error.status_mut() = StatusCode::FORBIDDEN;
+            Err(error)
+        };
+        let mut socket = tungstenite::accept_hdr(stream, check_origin)
+            .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {e}"))?;
+        loop {
+            let request = match socket.read() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let response = self.handle(&request);
+            socket.send(Message::Text(response.to_string()))?;
+        }
+    }
+
+    /// Handle a single JSON-RPC request and return the response.
+    pub fn handle(&mut self, request: &str) -> Value {
+        let (id, result) = match serde_json::from_str::<Request>(request) {
+            Ok(request) => {
+                log::debug!("[serve] {} {}", request.method, request.params);
+                (request.id, self.call(&request.method, request.params))
+            }
+            Err(e) => (Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+        };
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        }
+    }
+
+    fn call(&mut self, method: &str, p: Value) -> Result<Value, RpcError> {
+        if method == "pvms" {
+            let names: Vec<_> = self.debugger.state().into_iter().map(|s| s.name).collect();
+            return Ok(json!(names));
+        }
+        if method == "load" {
+            let LoadParams { testcase, path } = params(p)?;
+            let json = match (testcase, path) {
+                (Some(testcase), None) => testcase,
+                (None, Some(path)) => runner::load_testcase(&self.testcase_path(&path)?)?,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "Expected either `testcase` or `path`.")),
+            };
+            self.loaded = false;
+            self.debugger.load(json)?;
+            self.loaded = true;
+            return Ok(self.state());
+        }
+        if !self.loaded && method != "breakpoints" && method != "setBreakpoints" {
+            return Err(RpcError::new(SERVER_ERROR, "No test case loaded. Call `load` first."));
+        }
+
+        match method {
+            "reset" => self.debugger.reset()?,
+            "step" => {
+                let StepParams { count } = params(p)?;
+                self.debugger.step(count.unwrap_or(1))?;
+            }
+            "run" => self.debugger.resume()?,
+            "state" => {}
+            "setRegister" => {
+                let SetRegisterParams { index, value } = params(p)?;
+                if index >= api::NUMBER_OF_REGISTERS {
+                    return Err(RpcError::new(INVALID_PARAMS, format!("Invalid register: {index}")));
+                }
+                self.debugger.set_register(index, value.value()?);
+            }
+            "setGas" => {
+                let SetGasParams { gas } = params(p)?;
+                self.debugger.set_gas(gas.value()? as i64);
+            }
+            "memory" => {
+                let MemoryParams { address, length } = params(p)?;
+                if length > debugger::MAX_MEMORY_LENGTH {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        format!(
+                            "Length too large, at most {} bytes can be read.",
+                            debugger::MAX_MEMORY_LENGTH
+                        ),
+                    ));
+                }
+                let memory = self.debugger.read_memory(address, length);
+                let memory: Vec<_> = memory
+                    .into_iter()
+                    .zip(self.debugger.take_mismatches())
+                    .map(|((name, data), mismatches)| {
+                        let mismatches = mismatches_json(&mismatches);
+                        match data {
+                            Ok(data) => json!({ "name": name, "data": to_hex(&data), "mismatches": mismatches }),
+                            Err(e) => json!({ "name": name, "error": e.to_string(), "mismatches": mismatches }),
+                        }
+                    })
+                    .collect();
+                return Ok(json!(memory));
+            }
+            "writeMemory" => {
+                let WriteMemoryParams { address, data } = params(p)?;
+                let data = debugger::parse_hex(&data).map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+                self.debugger.write_memory(address, &data)?;
+            }
+            "breakpoints" => return Ok(json!(self.debugger.breakpoints())),
+            "setBreakpoints" => {
+                let BreakpointsParams { pcs } = params(p)?;
+                *self.debugger.breakpoints_mut() = pcs.into_iter().collect();
+                return Ok(json!(self.debugger.breakpoints()));
+            }
+            other => return Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method: {other}"))),
+        }
+        Ok(self.state())
+    }
+
+    /// Make sure the file is inside one of the test directories, so clients can't read arbitrary files.
+    fn testcase_path(&self, path: &Path) -> Result<PathBuf, RpcError> {
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{}: {e}", path.display())))?;
+        if !self.tests.iter().any(|dir| canonical.starts_with(dir)) {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "Only files from the configured test directories can be loaded.",
+            ));
+        }
+        Ok(canonical)
+    }
+
+    fn state(&self) -> Value {
+        let state = self.debugger.state();
+        let state: Vec<_> = state
+            .into_iter()
+            .zip(self.debugger.take_mismatches())
+            .map(|(s, mismatches)| {
+                let registers: Vec<_> = s.registers.iter().map(|r| r.to_string()).collect();
+                json!({
+                    "name": s.name,
+                    "status": s.status.map(|s| s.to_string()),
+                    "faultAddress": s.status.and_then(|s| s.fault_address()),
+                    "pc": s.pc,
+                    "gas": s.gas.to_string(),
+                    "registers": registers,
+                    "mismatches": mismatches_json(&mismatches),
+                })
+            })
+            .collect();
+        json!(state)
+    }
+}
+
+fn mismatches_json(mismatches: &[Mismatch]) -> Value {
+    let mismatches: Vec<_> = mismatches
+        .iter()
+        .map(|m| {
+            let values: Vec<_> = m
+                .values
+                .iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect();
+            json!({ "field": m.field, "values": values, "wrong": m.wrong })
+        })
+        .collect();
+    json!(mismatches)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let hex: String = data.iter().map(|b| format!("{b:02x}")).collect();
+    format!("0x{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{collection::PvmApiCollection, reference::Reference};
+
+    fn server(tests: &[PathBuf]) -> Server {
+        Server::new(vec![("reference".into(), Box::new(Reference::default()))], tests)
+    }
+
+    fn error_code(response: &Value) -> Option<i64> {
+        response["error"]["code"].as_i64()
+    }
+
+    const TESTCASE: &str = r#"{
+        "name": "trap", "initial-regs": [0,0,0,0,0,0,0,0,0,0,0,0,0], "initial-pc": 0,
+        "initial-page-map": [], "initial-memory": [], "initial-gas": 10, "program": [0, 0, 1, 0, 1],
+        "expected-status": "panic", "expected-regs": [0,0,0,0,0,0,0,0,0,0,0,0,0], "expected-pc": 0,
+        "expected-memory": [], "expected-gas": 9
+    }"#;
+
+    #[test]
+    fn memory_reads_are_capped() {
+        let mut server = server(&[]);
+        let load = format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "load", "params": {{"testcase": {TESTCASE}}}}}"#);
+        assert_eq!(error_code(&server.handle(&load)), None);
+
+        let read = |length: u32| {
+            format!(
+                r#"{{"jsonrpc": "2.0", "id": 2, "method": "memory", "params": {{"address": 0, "length": {length}}}}}"#
+            )
+        };
+        assert_eq!(error_code(&server.handle(&read(debugger::MAX_MEMORY_LENGTH))), None);
+        assert_eq!(error_code(&server.handle(&read(u32::MAX))), Some(INVALID_PARAMS));
+    }
+
+    #[test]
+    fn loads_only_from_test_directories() {
+        let dir = std::env::temp_dir().join(format!("pvm-server-test-{}", std::process::id()));
+        let tests = dir.join("tests");
+        std::fs::create_dir_all(&tests).unwrap();
+        std::fs::write(tests.join("trap.json"), TESTCASE).unwrap();
+        std::fs::write(dir.join("outside.json"), TESTCASE).unwrap();
+
+        let mut server = server(std::slice::from_ref(&tests));
+        let load = |path: &Path| {
+            let params = json!({ "path": path });
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "load", "params": params }).to_string()
+        };
+        assert_eq!(error_code(&server.handle(&load(&tests.join("trap.json")))), None);
+        assert_eq!(
+            error_code(&server.handle(&load(&dir.join("outside.json")))),
+            Some(INVALID_PARAMS)
+        );
+        assert_eq!(
+            error_code(&server.handle(&load(&tests.join("../outside.json")))),
+            Some(INVALID_PARAMS)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Reports one more gas than the reference PVM.
+    #[derive(Default)]
+    struct Skewed(Reference);
+
+    impl PvmApi for Skewed {
+        fn run(&mut self) -> api::Result<api::Status> {
+            self.0.run()
+        }
+        fn step(&mut self) -> api::Result<api::Status> {
+            self.0.step()
+        }
+        fn gas(&self) -> i64 {
+            self.0.gas() + 1
+        }
+        fn set_gas(&mut self, gas: i64) {
+            self.0.set_gas(gas)
+        }
+        fn registers(&self) -> [u64; api::NUMBER_OF_REGISTERS] {
+            self.0.registers()
+        }
+        fn set_registers(&mut self, registers: &[u64; api::NUMBER_OF_REGISTERS]) {
+            self.0.set_registers(registers)
+        }
+        fn program_counter(&self) -> Option<u32> {
+            self.0.program_counter()
+        }
+        fn set_next_program_counter(&mut self, pc: u32) {
+            self.0.set_next_program_counter(pc)
+        }
+        fn set_program(&mut self, code: &[u8], container: api::ProgramContainer) -> api::Result<()> {
+            self.0.set_program(code, container)
+        }
+        fn set_page(&mut self, page: u32, access: api::MemoryAccess) {
+            self.0.set_page(page, access)
+        }
+        fn read_memory(&self, address: u32, out: &mut [u8]) -> api::Result<()> {
+            self.0.read_memory(address, out)
+        }
+        fn write_memory(&mut self, address: u32, data: &[u8]) -> api::Result<()> {
+            self.0.write_memory(address, data)
+        }
+    }
+
+    #[test]
+    fn reports_collection_mismatches() {
+        let pvms: Vec<(String, Box<dyn PvmApi>)> = vec![
+            ("reference".into(), Box::new(Reference::default())),
+            ("skewed".into(), Box::new(Skewed::default())),
+        ];
+        let collection = PvmApiCollection::new(pvms);
+        let mut server = Server::new(vec![("collection".into(), Box::new(collection))], &[]);
+        let load = format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "load", "params": {{"testcase": {TESTCASE}}}}}"#);
+        let response = server.handle(&load);
+        let mismatches = &response["result"][0]["mismatches"];
+        assert_eq!(mismatches[0]["field"], "gas");
+        assert_eq!(mismatches[0]["wrong"], json!(["skewed"]));
+
+        // reported mismatches are drained, so they don't pile up between requests.
+        let state = r#"{"jsonrpc": "2.0", "id": 2, "method": "state"}"#;
+        let response = server.handle(state);
+        assert_eq!(response["result"][0]["mismatches"].as_array().map(|m| m.len()), Some(1));
+    }
+
+    #[test]
+    fn origins() {
+        let allowed = vec!["https://pvm.fluffylabs.dev".to_string()];
+        assert!(is_allowed_origin(None, &[]));
+        assert!(is_allowed_origin(Some("http://localhost:3000"), &[]));
+        assert!(is_allowed_origin(Some("http://127.0.0.1"), &[]));
+        assert!(is_allowed_origin(Some("http://[::1]:8080"), &[]));
+        assert!(is_allowed_origin(Some("https://pvm.fluffylabs.dev"), &allowed));
+
+        assert!(!is_allowed_origin(Some("https://pvm.fluffylabs.dev"), &[]));
+        assert!(!is_allowed_origin(Some("http://localhost.evil.com"), &[]));
+        assert!(!is_allowed_origin(Some("null"), &allowed));
+    }
+}
@@ -0,0 +1,226 @@
+//! Per-instruction execution traces.
+//!
+//! A trace file is JSON Lines: the first line is a [`TraceHeader`], followed by one
+//! [`TraceStep`] per executed instruction, e.g.:
+//!
+//! ```text
+//! {"pvm":"polkavm","testcase":"inst_store_u8"}
+//! {"pc":0,"opcode":51,"status":"ok","gas":9999,"regs":{"7":131072},"memory":[]}
+//! {"pc":3,"opcode":59,"status":"ok","gas":9998,"regs":{},"memory":[{"address":131072,"contents":[42]}]}
+//! ```
+//!
+//! `pc` and `opcode` describe the executed instruction, while the remaining fields describe
+//! the state after it: `regs` contains only registers that changed and `memory` contains
+//! contiguous ranges of writable memory that changed. `fault-address` is only present for
+//! `page-fault` status. PVMs that can't be stepped produce
+//! a single step covering the whole execution.
+//!
+//! If the execution didn't finish within the step limit, the header has `"truncated": true`.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    api::{self, PvmApi, Status, PAGE_SIZE},
+    json::{MemoryChunk, TestcaseJson},
+    program::GenericProgram,
+    runner,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TraceHeader {
+    pub pvm: String,
+    pub testcase: String,
+    /// The step limit was reached before the execution finished.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TraceStep {
+    pub pc: u32,
+    pub opcode: Option<u8>,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault_address: Option<u32>,
+    pub gas: i64,
+    pub regs: BTreeMap<usize, u64>,
+    pub memory: Vec<MemoryChunk>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub header: TraceHeader,
+    pub steps: Vec<TraceStep>,
+}
+
+impl Trace {
+    pub fn write(&self, mut out: impl Write) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut out, &self.header)?;
+        writeln!(out)?;
+        for step in &self.steps {
+            serde_json::to_writer(&mut out, step)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(input: impl BufRead) -> anyhow::Result<Self> {
+        let mut lines = input.lines();
+        let header = lines.next().ok_or_else(|| anyhow::anyhow!("Empty trace file."))??;
+        let header = serde_json::from_str(&header)?;
+        let steps = lines
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { header, steps })
+    }
+}
+
+/// Execute the test case instruction by instruction, recording the trace.
+pub fn record(pvm: &mut dyn PvmApi, pvm_name: &str, json: &TestcaseJson, max_steps: usize) -> api::Result<Trace> {
+    runner::setup_testcase(pvm, json)?;
+    let program = GenericProgram::parse(&json.program).unwrap_or_default();
+
+    // Snapshot of all writable pages, to detect memory writes.
+    let mut memory = BTreeMap::new();
+    for page in json.initial_page_map.iter().filter(|p| p.is_writable) {
+        let mut address = page.address;
+        while u64::from(address) < u64::from(page.address) + u64::from(page.length) {
+            let mut data = vec![0u8; PAGE_SIZE as usize];
+            if pvm.read_memory(address, &mut data).is_ok() {
+                memory.insert(address, data);
+            }
+            address = match address.checked_add(PAGE_SIZE) {
+                Some(a) => a,
+                None => break,
+            };
+        }
+    }
+
+    let mut steps = vec![];
+    let mut pc = pvm.program_counter().unwrap_or(json.initial_pc);
+    let mut regs = json.initial_regs;
+    let mut truncated = true;
+    for _ in 0..max_steps {
+        let status = pvm.step()?;
+
+        let new_regs = pvm.registers();
+        let changed_regs = (0..api::NUMBER_OF_REGISTERS)
+            .filter(|i| regs[*i] != new_regs[*i])
+            .map(|i| (i, new_regs[i]))
+            .collect();
+        regs = new_regs;
+
+        let mut writes = vec![];
+        for (address, data) in memory.iter_mut() {
+            let mut new_data = vec![0u8; data.len()];
+            if pvm.read_memory(*address, &mut new_data).is_ok() {
+                writes.extend(diff_memory(*address, data, &new_data));
+                *data = new_data;
+            }
+        }
+
+        steps.push(TraceStep {
+            pc,
+            opcode: program.opcode_at(pc),
+            status: status.to_string(),
+            fault_address: status.fault_address(),
+            gas: pvm.gas(),
+            regs: changed_regs,
+            memory: writes,
+        });
+
+        if status != Status::Ok {
+            truncated = false;
+            break;
+        }
+        pc = match pvm.program_counter() {
+            Some(pc) => pc,
+            None => {
+                truncated = false;
+                break;
+            }
+        };
+    }
+
+    Ok(Trace {
+        header: TraceHeader {
+            pvm: pvm_name.into(),
+            testcase: json.name.clone(),
+            truncated,
+        },
+        steps,
+    })
+}
+
+/// Contiguous ranges of bytes that differ between `old` and `new`.
+fn diff_memory(address: u32, old: &[u8], new: &[u8]) -> Vec<MemoryChunk> {
+    let mut chunks: Vec<MemoryChunk> = vec![];
+    let mut last = None;
+    for (i, (_, byte)) in old.iter().zip(new).enumerate().filter(|(_, (a, b))| a != b) {
+        match chunks.last_mut() {
+            Some(chunk) if last.map(|l| l + 1) == Some(i) => chunk.contents.push(*byte),
+            _ => chunks.push(MemoryChunk {
+                address: address + i as u32,
+                contents: vec![*byte],
+            }),
+        }
+        last = Some(i);
+    }
+    chunks
+}
+
+/// The first point where two traces disagree.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Index of the first differing step.
+    pub step: usize,
+    /// Names of the differing fields.
+    pub fields: Vec<String>,
+}
+
+/// Align two traces step by step and find the first divergence.
+pub fn diff(a: &Trace, b: &Trace) -> Option<Divergence> {
+    for (step, (x, y)) in a.steps.iter().zip(&b.steps).enumerate() {
+        let mut fields = vec![];
+        if x.pc != y.pc {
+            fields.push("pc".to_string());
+        }
+        if x.opcode != y.opcode {
+            fields.push("opcode".into());
+        }
+        if x.status != y.status {
+            fields.push("status".into());
+        }
+        if x.fault_address != y.fault_address {
+            fields.push("fault-address".into());
+        }
+        if x.gas != y.gas {
+            fields.push("gas".into());
+        }
+        for reg in x.regs.keys().chain(y.regs.keys()) {
+            let name = format!("reg {reg}");
+            if x.regs.get(reg) != y.regs.get(reg) && !fields.contains(&name) {
+                fields.push(name);
+            }
+        }
+        if x.memory != y.memory {
+            fields.push("memory".into());
+        }
+        if !fields.is_empty() {
+            return Some(Divergence { step, fields });
+        }
+    }
+
+    if a.steps.len() != b.steps.len() {
+        return Some(Divergence {
+            step: a.steps.len().min(b.steps.len()),
+            fields: vec!["length".into()],
+        });
+    }
+    None
+}
@@ -0,0 +1,248 @@
+//! Generation of single-instruction conformance test vectors.
+//!
+//! For every opcode, test cases executing just that instruction are created with all
+//! combinations of boundary operands. The expectations are taken from a reference PVM.
+
+use std::{fs::File, path::Path};
+
+use anyhow::Context;
+
+use crate::{
+    api::{Isa, PvmApi, NUMBER_OF_REGISTERS, PAGE_SIZE},
+    bridge,
+    fuzz::generate,
+    instruction::{self, sign_extend_32, Args, Kind, Opcode, HALT_ADDRESS},
+    json::{MemoryChunk, Page, TestcaseJson},
+    program::GenericProgram,
+};
+
+/// Register operands: zero, one, minus one, 32- and 64-bit limits and shift amounts at and past the width.
+pub const OPERANDS: &[u64] = &[
+    0,
+    1,
+    u64::MAX,
+    i32::MIN as i64 as u64,
+    i32::MAX as u64,
+    u32::MAX as u64,
+    i64::MIN as u64,
+    i64::MAX as u64,
+    31,
+    32,
+    63,
+    64,
+];
+/// Writable page mapped by every test case.
+const MEMORY: u32 = 0x20000;
+/// Addresses accessed by loads and stores: in the page, crossing its end, past its end and null.
+const ADDRESSES: &[u32] = &[MEMORY, MEMORY + PAGE_SIZE - 4, MEMORY + PAGE_SIZE, 0];
+/// Registers holding the operands, and the destination of three-register instructions.
+const A: u8 = 7;
+const B: u8 = 8;
+const D: u8 = 9;
+
+/// The instruction's arguments along with the initial registers.
+type Case = (Args, [u64; NUMBER_OF_REGISTERS]);
+
+/// `OPERANDS` which can be encoded as (sign-extended 32-bit) immediates.
+fn immediates() -> Vec<u64> {
+    let mut immediates: Vec<_> = OPERANDS.iter().map(|v| sign_extend_32(*v as u32)).collect();
+    immediates.sort();
+    immediates.dedup();
+    immediates
+}
+
+fn case(regs: [u8; 3], imms: [u64; 2], values: &[(u8, u64)]) -> Case {
+    let mut registers = [0; NUMBER_OF_REGISTERS];
+    for (register, value) in values {
+        registers[*register as usize] = *value;
+    }
+    (Args { regs, imms }, registers)
+}
+
+/// Jump targets for indirect jumps: the only jump table entry, misaligned, past the table, halt and overflowing.
+fn jump_targets() -> Vec<u64> {
+    vec![0, 1, 2, 3, 4, sign_extend_32(HALT_ADDRESS), u64::MAX, (1 << 32) | 2]
+}
+
+/// Test cases for given opcode, jump targets are filled in by `program`.
+fn cases(opcode: &Opcode) -> Vec<Case> {
+    let immediates = immediates();
+    let mut cases = vec![];
+    match opcode.kind {
+        Kind::NoArgs | Kind::OneOffset => cases.push(case([0; 3], [0; 2], &[])),
+        Kind::OneImm => {
+            for x in &immediates {
+                cases.push(case([0; 3], [*x, 0], &[]));
+            }
+        }
+        Kind::OneRegExtImm => {
+            for x in OPERANDS {
+                cases.push(case([A, 0, 0], [*x, 0], &[]));
+            }
+        }
+        Kind::TwoImm => {
+            for address in ADDRESSES {
+                for y in &immediates {
+                    cases.push(case([0; 3], [sign_extend_32(*address), *y], &[]));
+                }
+            }
+        }
+        Kind::OneRegOneImm if opcode.name == "jump_ind" => {
+            for value in jump_targets() {
+                for x in [0, 2, u64::MAX] {
+                    cases.push(case([A, 0, 0], [x, 0], &[(A, value)]));
+                }
+            }
+        }
+        Kind::OneRegOneImm if opcode.is_load() => {
+            for address in ADDRESSES {
+                cases.push(case([A, 0, 0], [sign_extend_32(*address), 0], &[]));
+            }
+        }
+        Kind::OneRegOneImm if opcode.is_store() => {
+            for address in ADDRESSES {
+                for value in OPERANDS {
+                    cases.push(case([A, 0, 0], [sign_extend_32(*address), 0], &[(A, *value)]));
+                }
+            }
+        }
+        Kind::OneRegOneImm => {
+            for x in &immediates {
+                cases.push(case([A, 0, 0], [*x, 0], &[]));
+            }
+        }
+        Kind::OneRegTwoImm => {
+            for address in ADDRESSES {
+                for y in &immediates {
+                    cases.push(case([A, 0, 0], [0, *y], &[(A, u64::from(*address))]));
+                }
+            }
+        }
+        Kind::OneRegImmOffset => {
+            for value in OPERANDS {
+                for x in &immediates {
+                    cases.push(case([A, 0, 0], [*x, 0], &[(A, *value)]));
+                }
+            }
+        }
+        Kind::TwoReg => {
+            for value in OPERANDS {
+                cases.push(case([A, B, 0], [0; 2], &[(B, *value)]));
+            }
+        }
+        Kind::TwoRegOneImm if opcode.is_load() => {
+            for address in ADDRESSES {
+                cases.push(case([A, B, 0], [0; 2], &[(B, u64::from(*address))]));
+            }
+        }
+        Kind::TwoRegOneImm if opcode.is_store() => {
+            for address in ADDRESSES {
+                for value in OPERANDS {
+                    cases.push(case([A, B, 0], [0; 2], &[(A, *value), (B, u64::from(*address))]));
+                }
+            }
+        }
+        Kind::TwoRegOneImm => {
+            for value in OPERANDS {
+                for x in &immediates {
+                    cases.push(case([A, B, 0], [*x, 0], &[(B, *value)]));
+                }
+            }
+        }
+        Kind::TwoRegOneOffset | Kind::ThreeReg => {
+            for a in OPERANDS {
+                for b in OPERANDS {
+                    cases.push(case([A, B, D], [0; 2], &[(A, *a), (B, *b)]));
+                }
+            }
+        }
+        Kind::TwoRegTwoImm => {
+            for value in jump_targets() {
+                for y in [0, 2, u64::MAX] {
+                    cases.push(case([A, B, 0], [u64::MAX, y], &[(B, value)]));
+                }
+            }
+        }
+    }
+    cases
+}
+
+/// The program: the instruction, followed by a trap and a halt (at the jump target) for terminators.
+fn program(opcode: &Opcode, args: &Args) -> Vec<u8> {
+    let mut args = *args;
+    // the trap is a single byte and the length of the instruction doesn't depend on the target.
+    let target = instruction::encode(opcode, &args, 0).len() as u64 + 1;
+    match opcode.kind {
+        Kind::OneOffset | Kind::TwoRegOneOffset => args.imms[0] = target,
+        Kind::OneRegImmOffset => args.imms[1] = target,
+        _ => {}
+    }
+
+    let mut program = GenericProgram::default();
+    program.push(opcode, &args);
+    if opcode.is_terminator() {
+        program.push(generate::op("trap"), &Args::default());
+        program.jump_table.push(program.code.len() as u32);
+        let halt = Args {
+            regs: [0; 3],
+            imms: [sign_extend_32(HALT_ADDRESS), 0],
+        };
+        program.push(generate::op("load_imm"), &halt);
+        program.push(generate::op("jump_ind"), &Args::default());
+    }
+    program.encode()
+}
+
+/// Test cases (without expectations) for every opcode.
+pub fn testcases() -> Vec<TestcaseJson> {
+    let mut testcases = vec![];
+    for opcode in instruction::OPCODES {
+        for (index, (args, initial_regs)) in cases(opcode).into_iter().enumerate() {
+            testcases.push(TestcaseJson {
+                name: format!("inst_{}_gen_{index:03}", opcode.name),
+                initial_regs,
+                initial_page_map: vec![Page {
+                    address: MEMORY,
+                    length: PAGE_SIZE,
+                    is_writable: true,
+                }],
+                initial_memory: vec![MemoryChunk {
+                    address: MEMORY + PAGE_SIZE - 8,
+                    contents: vec![0x80, 0x81, 0xff, 0x7f, 0x01, 0x02, 0xfe, 0xff],
+                }],
+                initial_gas: 10_000,
+                program: program(opcode, &args),
+                ..Default::default()
+            });
+        }
+    }
+    testcases
+}
+
+/// Generate the test vectors with expectations from `reference` and write them to
+/// `output/pvm/programs/<name>.json` (jamtestvectors layout).
+pub fn generate(reference: &mut dyn PvmApi, output: &Path, isa: Option<Isa>) -> anyhow::Result<()> {
+    let dir = output.join("pvm").join("programs");
+    std::fs::create_dir_all(&dir).with_context(|| "Failed to create output directory.".to_string())?;
+
+    let (mut written, mut failed) = (0, 0);
+    for mut json in testcases() {
+        if let Some(isa) = isa {
+            json.isa = isa;
+        }
+        let name = json.name.clone();
+        match bridge::execute(reference, json) {
+            Ok(json) => {
+                let path = dir.join(format!("{name}.json"));
+                serde_json::to_writer_pretty(File::create(&path)?, &json)?;
+                written += 1;
+            }
+            Err(e) => {
+                failed += 1;
+                log::error!("[gen-vectors] Skipping {name}: {e:?}");
+            }
+        }
+    }
+    println!("{written} test vectors written to {}, {failed} failed.", dir.display());
+    Ok(())
+}